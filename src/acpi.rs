@@ -0,0 +1,254 @@
+//! Discovery of the ACPI tables and decoding of the interrupt controller layout in the MADT.
+
+use alloc::vec::Vec;
+use core::{mem, ptr};
+
+use x86_64::PhysAddr;
+
+use crate::memory::{self, AreaKind};
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// The size of the RSDP of ACPI 1.0, which the checksum covers.
+const RSDP_V1_SIZE: usize = 20;
+/// The size of the RSDP since ACPI 2.0, which the extended checksum covers.
+const RSDP_V2_SIZE: usize = 36;
+/// The size of the header every system description table starts with.
+const SDT_HEADER_SIZE: usize = 36;
+
+/// The BIOS data area holds the real mode segment of the extended BIOS data area here.
+const EBDA_SEGMENT_POINTER: u64 = 0x40e;
+/// The RSDP lies in the first KiB of the EBDA or in the BIOS ROM, on a 16-byte boundary.
+const EBDA_SEARCH_SIZE: u64 = 1024;
+const BIOS_ROM_START: u64 = 0xe_0000;
+const BIOS_ROM_END: u64 = 0x10_0000;
+
+/// The MADT flag that indicates that the legacy 8259 PICs are installed as well.
+const MADT_PCAT_COMPAT: u32 = 1;
+/// The offset of the first interrupt controller structure in the MADT.
+const MADT_ENTRIES_OFFSET: usize = SDT_HEADER_SIZE + 8;
+
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// A processor's local APIC that can be used, either right away or once it is brought online.
+const LOCAL_APIC_ENABLED: u32 = 1;
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 2;
+
+/// An I/O APIC, which routes a range of global system interrupts to the local APICs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// The global system interrupt of the first input pin.
+    pub gsi_base: u32,
+}
+
+/// How an interrupt line signals an interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptRouting {
+    /// The global system interrupt the line is connected to.
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// A legacy ISA interrupt that isn't connected to the I/O APIC input of the same number, or
+/// doesn't use the ISA defaults of active high and edge triggered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub isa_irq: u8,
+    pub routing: InterruptRouting,
+}
+
+/// The interrupt controllers described by the multiple APIC description table.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// The local APIC IDs of the usable processors.
+    pub local_apic_ids: Vec<u8>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub interrupt_overrides: Vec<InterruptOverride>,
+    /// Whether the legacy 8259 PICs are installed as well, and need to be masked.
+    pub has_legacy_pics: bool,
+}
+
+impl Madt {
+    /// Returns how a legacy ISA interrupt is routed to the I/O APICs, or `None` if its input
+    /// was taken over by another interrupt, like the cascade input IRQ 2 usually is by the PIT.
+    pub fn isa_routing(&self, isa_irq: u8) -> Option<InterruptRouting> {
+        if let Some(interrupt_override) = self
+            .interrupt_overrides
+            .iter()
+            .find(|interrupt_override| interrupt_override.isa_irq == isa_irq)
+        {
+            return Some(interrupt_override.routing);
+        }
+        let gsi = isa_irq.into();
+        if self
+            .interrupt_overrides
+            .iter()
+            .any(|interrupt_override| interrupt_override.routing.gsi == gsi)
+        {
+            return None;
+        }
+        Some(InterruptRouting {
+            gsi,
+            active_low: false,
+            level_triggered: false,
+        })
+    }
+}
+
+/// Finds the root system description pointer in the BIOS memory areas.
+pub fn find_rsdp() -> Option<PhysAddr> {
+    let ebda_start = u64::from(read_physical::<u16>(EBDA_SEGMENT_POINTER)?) << 4;
+    let candidates = (ebda_start..ebda_start + EBDA_SEARCH_SIZE)
+        .step_by(16)
+        .chain((BIOS_ROM_START..BIOS_ROM_END).step_by(16));
+    for address in candidates {
+        if read_physical::<[u8; 8]>(address)? != *RSDP_SIGNATURE
+            || !has_valid_checksum(address, RSDP_V1_SIZE)
+        {
+            continue;
+        }
+        // Only revision 2 and later have the extended fields
+        let revision: u8 = read_physical(address + 15)?;
+        if revision < 2 || has_valid_checksum(address, RSDP_V2_SIZE) {
+            return Some(PhysAddr::new(address));
+        }
+    }
+    None
+}
+
+/// Finds the system description table with the given signature, e.g. `b"APIC"` for the MADT,
+/// and returns its physical address.
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let rsdp = find_rsdp()?.as_u64();
+    let revision: u8 = read_physical(rsdp + 15)?;
+    // The XSDT has 64-bit entries, and supersedes the RSDT if present
+    let (root, entry_size) = match read_physical::<u64>(rsdp + 24) {
+        Some(xsdt) if revision >= 2 && xsdt != 0 => (xsdt, 8),
+        _ => (u64::from(read_physical::<u32>(rsdp + 16)?), 4),
+    };
+    let root_length = checked_table_length(root)?;
+
+    (SDT_HEADER_SIZE..root_length)
+        .step_by(entry_size)
+        .filter_map(|offset| {
+            let entry = root + offset as u64;
+            if entry_size == 8 {
+                read_physical::<u64>(entry)
+            } else {
+                read_physical::<u32>(entry).map(u64::from)
+            }
+        })
+        .find(|&table| {
+            read_physical::<[u8; 4]>(table) == Some(*signature)
+                && checked_table_length(table).is_some()
+        })
+        .map(PhysAddr::new)
+}
+
+/// Finds and decodes the MADT.
+pub fn madt() -> Option<Madt> {
+    let table = find_table(b"APIC")?.as_u64();
+    let length = checked_table_length(table)?;
+
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(read_physical::<u32>(table + 36)?.into()),
+        local_apic_ids: Vec::new(),
+        io_apics: Vec::new(),
+        interrupt_overrides: Vec::new(),
+        has_legacy_pics: read_physical::<u32>(table + 40)? & MADT_PCAT_COMPAT != 0,
+    };
+
+    let mut offset = MADT_ENTRIES_OFFSET;
+    while offset + 2 <= length {
+        let entry = table + offset as u64;
+        let entry_type: u8 = read_physical(entry)?;
+        let entry_length = usize::from(read_physical::<u8>(entry + 1)?);
+        if entry_length < 2 || offset + entry_length > length {
+            break;
+        }
+        match entry_type {
+            MADT_LOCAL_APIC if entry_length >= 8 => {
+                let flags: u32 = read_physical(entry + 4)?;
+                if flags & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0 {
+                    madt.local_apic_ids.push(read_physical(entry + 3)?);
+                }
+            }
+            MADT_IO_APIC if entry_length >= 12 => madt.io_apics.push(IoApicInfo {
+                id: read_physical(entry + 2)?,
+                address: PhysAddr::new(read_physical::<u32>(entry + 4)?.into()),
+                gsi_base: read_physical(entry + 8)?,
+            }),
+            MADT_INTERRUPT_OVERRIDE if entry_length >= 10 => {
+                let flags: u16 = read_physical(entry + 8)?;
+                // A polarity or trigger mode of 0 means the bus default, which is active high
+                // and edge triggered for ISA
+                madt.interrupt_overrides.push(InterruptOverride {
+                    isa_irq: read_physical(entry + 3)?,
+                    routing: InterruptRouting {
+                        gsi: read_physical(entry + 4)?,
+                        active_low: flags & 0x3 == 0x3,
+                        level_triggered: (flags >> 2) & 0x3 == 0x3,
+                    },
+                });
+            }
+            MADT_LOCAL_APIC_ADDRESS_OVERRIDE if entry_length >= 12 => {
+                madt.local_apic_address = PhysAddr::new(read_physical(entry + 4)?);
+            }
+            _ => {}
+        }
+        offset += entry_length;
+    }
+    Some(madt)
+}
+
+/// Returns the length of the table at the given address if its checksum is valid.
+fn checked_table_length(table: u64) -> Option<usize> {
+    let length = read_physical::<u32>(table + 4)? as usize;
+    if length < SDT_HEADER_SIZE || !has_valid_checksum(table, length) {
+        return None;
+    }
+    Some(length)
+}
+
+/// ACPI structures are valid if all of their bytes add up to zero.
+fn has_valid_checksum(address: u64, length: usize) -> bool {
+    (address..address + length as u64).try_fold(0u8, |sum, byte| {
+        Some(sum.wrapping_add(read_physical::<u8>(byte)?))
+    }) == Some(0)
+}
+
+/// Reads a value from physical memory through the physical memory mapping, or returns `None` if
+/// the address lies outside of it.
+fn read_physical<T: Copy>(address: u64) -> Option<T> {
+    let physical_memory = memory::area(AreaKind::PhysicalMemory);
+    let end = address.checked_add(mem::size_of::<T>() as u64)?;
+    if end > physical_memory.size {
+        return None;
+    }
+    let pointer: *const T = (physical_memory.start + address).as_ptr();
+    // ACPI structures are packed, so fields may be misaligned
+    Some(unsafe { ptr::read_unaligned(pointer) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_madt_describes_apics() {
+        let madt = madt().expect("no MADT found");
+        assert_eq!(madt.local_apic_address, PhysAddr::new(0xfee0_0000));
+        assert!(!madt.local_apic_ids.is_empty());
+        assert!(!madt.io_apics.is_empty());
+        // QEMU connects the PIT to the second I/O APIC input, like most PCs
+        assert_eq!(madt.isa_routing(0).map(|routing| routing.gsi), Some(2));
+        assert_eq!(madt.isa_routing(1).map(|routing| routing.gsi), Some(1));
+        assert_eq!(madt.isa_routing(2), None);
+    }
+}
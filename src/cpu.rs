@@ -0,0 +1,142 @@
+//! CPU feature detection and security hardening of the control registers.
+
+use core::{
+    arch::{
+        asm,
+        x86_64::{__cpuid_count, CpuidResult},
+    },
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use log::info;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+
+/// Protections the kernel turns on when the CPU supports them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecurityFeature {
+    /// Supervisor mode execution prevention: the kernel can't execute user pages.
+    Smep,
+    /// Supervisor mode access prevention: the kernel can't access user pages, except through
+    /// [`with_user_access`].
+    Smap,
+    /// User mode instruction prevention: user mode can't run `sgdt`, `sidt` and friends.
+    Umip,
+}
+
+impl SecurityFeature {
+    pub const ALL: [SecurityFeature; 3] = [Self::Smep, Self::Smap, Self::Umip];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Smep => "SMEP",
+            Self::Smap => "SMAP",
+            Self::Umip => "UMIP",
+        }
+    }
+
+    /// Returns whether CPUID reports the feature as supported.
+    pub fn is_supported(self) -> bool {
+        // The structured extended feature flags are in leaf 7, subleaf 0
+        let features = cpuid(7, 0);
+        match self {
+            Self::Smep => features.ebx & (1 << 7) != 0,
+            Self::Smap => features.ebx & (1 << 20) != 0,
+            Self::Umip => features.ecx & (1 << 2) != 0,
+        }
+    }
+
+    /// Returns whether the feature is turned on in CR4.
+    pub fn is_enabled(self) -> bool {
+        Cr4::read().contains(self.cr4_flag())
+    }
+
+    fn cr4_flag(self) -> Cr4Flags {
+        match self {
+            Self::Smep => Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION,
+            Self::Smap => Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION,
+            Self::Umip => Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION,
+        }
+    }
+}
+
+/// Returns whether the CPU has a local APIC, from CPUID leaf 1, EDX bit 9.
+pub fn has_apic() -> bool {
+    cpuid(1, 0).edx & (1 << 9) != 0
+}
+
+/// Returns whether the CPU has a page attribute table, from CPUID leaf 1, EDX bit 16.
+pub fn has_pat() -> bool {
+    cpuid(1, 0).edx & (1 << 16) != 0
+}
+
+/// Returns whether the CPU supports 1 GiB pages, from CPUID leaf 0x8000_0001, EDX bit 26.
+pub fn has_gigantic_pages() -> bool {
+    cpuid(0x8000_0001, 0).edx & (1 << 26) != 0
+}
+
+/// Executes CPUID with the given leaf and subleaf. If the CPU doesn't implement the leaf, every
+/// register reads as zero, so that all of the leaf's feature bits read as unsupported.
+fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    // Leaf 0 reports the highest basic leaf and leaf 0x8000_0000 the highest extended leaf. The
+    // CPUID intrinsics are only safe functions on newer toolchains.
+    #[allow(unused_unsafe)]
+    let (max_leaf, result) = unsafe {
+        (
+            __cpuid_count(leaf & 0x8000_0000, 0).eax,
+            __cpuid_count(leaf, subleaf),
+        )
+    };
+    if leaf > max_leaf {
+        return CpuidResult {
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+        };
+    }
+    result
+}
+
+/// Whether SMAP is on, in which case `stac` and `clac` must bracket accesses to user memory.
+/// The instructions don't exist on CPUs without SMAP, so they can't be used unconditionally.
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turn on every supported [`SecurityFeature`] and log the status of each.
+pub fn enable_security_features() {
+    for feature in SecurityFeature::ALL {
+        if feature.is_supported() {
+            unsafe { Cr4::update(|flags| flags.insert(feature.cr4_flag())) };
+            info!("      {}: enabled", feature.name());
+        } else {
+            info!("      {}: not supported", feature.name());
+        }
+    }
+    SMAP_ENABLED.store(SecurityFeature::Smap.is_enabled(), Ordering::Relaxed);
+}
+
+/// Runs `f` with access to user pages allowed, for copying data to and from user memory.
+///
+/// Interrupts are disabled meanwhile, so that handlers never run with the protection lifted.
+pub fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    if !SMAP_ENABLED.load(Ordering::Relaxed) {
+        return f();
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        unsafe { asm!("stac", options(nostack)) };
+        let result = f();
+        unsafe { asm!("clac", options(nostack)) };
+        result
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_supported_security_features_are_enabled() {
+        for feature in SecurityFeature::ALL {
+            assert_eq!(feature.is_enabled(), feature.is_supported());
+        }
+    }
+}
@@ -1,5 +1,7 @@
 //! Global descriptor table initialization.
 
+use core::mem;
+
 use conquer_once::spin::Lazy;
 use x86_64::{
     instructions::{segmentation::set_cs, tables::load_tss},
@@ -10,16 +12,36 @@ use x86_64::{
     VirtAddr,
 };
 
+use crate::memory::KernelStack;
+
 /// The first stack in the interrupt stack table is meant for the double fault handler.
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+const DOUBLE_FAULT_STACK_SIZE: usize = 20 * 1024;
+
+/// The task state segment used until memory is initialized, whose double fault stack is a
+/// static array without a guard page.
+static BOOTSTRAP_TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+        static mut STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+        // Taking the address of a mutable static is only safe on newer toolchains
+        #[allow(unused_unsafe)]
+        let stack_start = VirtAddr::from_ptr(unsafe { core::ptr::addr_of!(STACK) });
+        stack_start + DOUBLE_FAULT_STACK_SIZE
+    };
+    tss
+});
+
 static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
     let mut tss = TaskStateSegment::new();
     tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-        const STACK_SIZE: usize = 20 * 1024;
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-        stack_start + STACK_SIZE
+        let stack = KernelStack::new(DOUBLE_FAULT_STACK_SIZE)
+            .expect("failed to allocate double fault stack");
+        let stack_top = stack.top();
+        // The TSS lives forever, so its stacks must never be unmapped
+        mem::forget(stack);
+        stack_top
     };
     tss
 });
@@ -29,10 +51,18 @@ struct Selectors {
     tss_selector: SegmentSelector,
 }
 
-static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
+static BOOTSTRAP_GDT: Lazy<(GlobalDescriptorTable, Selectors)> =
+    Lazy::new(|| new_global_descriptor_table(&BOOTSTRAP_TSS));
+
+static GDT: Lazy<(GlobalDescriptorTable, Selectors)> =
+    Lazy::new(|| new_global_descriptor_table(&TSS));
+
+fn new_global_descriptor_table(
+    tss: &'static TaskStateSegment,
+) -> (GlobalDescriptorTable, Selectors) {
     let mut gdt = GlobalDescriptorTable::new();
     let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-    let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
     (
         gdt,
         Selectors {
@@ -40,14 +70,28 @@ static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
             tss_selector,
         },
     )
-});
+}
 
-/// Set up the global descriptor table with a kernel code segment and a task state segment
-/// that contains known good stacks to use in case of an interrupt.
-pub fn initialize_global_descriptor_table() {
-    GDT.0.load();
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
+    gdt.0.load();
     unsafe {
-        set_cs(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        set_cs(gdt.1.code_selector);
+        load_tss(gdt.1.tss_selector);
     }
 }
+
+/// Set up a global descriptor table whose task state segment has a static double fault stack,
+/// so that double faults can be handled before memory is initialized.
+pub fn initialize_bootstrap_global_descriptor_table() {
+    load(&BOOTSTRAP_GDT);
+}
+
+/// Set up the global descriptor table with a kernel code segment and a task state segment
+/// that contains known good stacks to use in case of an interrupt. This replaces the bootstrap
+/// table.
+///
+/// The stacks are guard-paged kernel stacks from the kernel stack area, so memory must be
+/// initialized first.
+pub fn initialize_global_descriptor_table() {
+    load(&GDT);
+}
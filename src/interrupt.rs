@@ -1,17 +1,37 @@
-//! Interrupts, the programmable interrupt controller, and the interrupt descriptor table.
+//! Interrupts, the interrupt controllers, and the interrupt descriptor table.
+
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use conquer_once::spin::Lazy;
+use log::warn;
 use pic8259::ChainedPics;
-use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::{
+    instructions::{interrupts, port::Port},
+    structures::idt::InterruptDescriptorTable,
+};
 
 use crate::{gdt, sync::Mutex};
 
+pub use self::irq::{
+    handler_count, is_irq_masked, register_irq, unregister_irq, IrqError, IrqHandler,
+    IrqRegistration, IRQ_LINES, MAX_HANDLERS_PER_LINE,
+};
+pub use self::statistics::{
+    interrupt_count, interrupt_statistics, log_interrupt_statistics, InterruptStatistics,
+};
+
+pub mod apic;
 pub mod handlers;
+mod irq;
+mod statistics;
 
 /// User-defined interrupts start at index 32.
 pub const PIC_1_OFFSET: u8 = 32;
 /// The second PIC starts 8 positions away from the first.
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+/// While the APICs are in use, the masked PICs are remapped to the 16 vectors from here on, so
+/// that their spurious interrupts can't be mistaken for the IRQs the I/O APICs deliver.
+pub const MASKED_PIC_OFFSET: u8 = 0xe0;
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy)]
@@ -35,26 +55,119 @@ pub(crate) enum InterruptIndex {
     SecondaryATA,
 }
 
+impl InterruptIndex {
+    /// The IRQ line of the interrupt, as used by [`register_irq`].
+    pub fn irq_line(self) -> u8 {
+        self as u8 - PIC_1_OFFSET
+    }
+}
+
+/// The handlers of the kernel's own devices, which are registered at boot.
+const BUILT_IN_HANDLERS: [(InterruptIndex, IrqHandler); 3] = [
+    (InterruptIndex::Timer, handlers::timer_handler),
+    (InterruptIndex::Keyboard, handlers::keyboard_handler),
+    (InterruptIndex::Mouse, handlers::mouse_handler),
+];
+
+/// The command ports of the two PICs.
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+/// The data ports of the two PICs, which take the interrupt masks.
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_DATA: u16 = 0xa1;
+/// The OCW3 command that makes the next read of a command port return the in-service register.
+const READ_IN_SERVICE_REGISTER: u8 = 0x0b;
+/// Each PIC raises a spurious interrupt on its lowest priority line, which is IRQ 7 or IRQ 15.
+const SPURIOUS_LINES: [u8; 2] = [7, 15];
+/// The line of the first PIC that the second one is connected to.
+const CASCADE_LINE: u8 = 2;
+
+/// The hardware that delivers device interrupts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptController {
+    /// The legacy 8259 pair.
+    Pic,
+    /// The local APIC, fed by the I/O APICs.
+    Apic,
+}
+
 static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     let mut table = InterruptDescriptorTable::new();
+    // The x86_64 version we depend on has no entries for #CP, #HV and #VC yet, which are only
+    // raised if the kernel enables control-flow enforcement or runs as a secure guest
+    table
+        .divide_error
+        .set_handler_fn(handlers::divide_error_handler);
+    table.debug.set_handler_fn(handlers::debug_handler);
+    table
+        .non_maskable_interrupt
+        .set_handler_fn(handlers::non_maskable_interrupt_handler);
     table
         .breakpoint
         .set_handler_fn(handlers::breakpoint_handler);
+    table.overflow.set_handler_fn(handlers::overflow_handler);
     table
-        .page_fault
-        .set_handler_fn(handlers::page_fault_handler);
+        .bound_range_exceeded
+        .set_handler_fn(handlers::bound_range_exceeded_handler);
+    table
+        .invalid_opcode
+        .set_handler_fn(handlers::invalid_opcode_handler);
+    table
+        .device_not_available
+        .set_handler_fn(handlers::device_not_available_handler);
     unsafe {
         table
             .double_fault
             .set_handler_fn(handlers::double_fault_handler)
             .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
     }
-    table[InterruptIndex::Timer as usize].set_handler_fn(handlers::timer_handler);
-    table[InterruptIndex::Keyboard as usize].set_handler_fn(handlers::keyboard_handler);
-    table[InterruptIndex::Mouse as usize].set_handler_fn(handlers::mouse_handler);
+    table
+        .invalid_tss
+        .set_handler_fn(handlers::invalid_tss_handler);
+    table
+        .segment_not_present
+        .set_handler_fn(handlers::segment_not_present_handler);
+    table
+        .stack_segment_fault
+        .set_handler_fn(handlers::stack_segment_fault_handler);
+    table
+        .general_protection_fault
+        .set_handler_fn(handlers::general_protection_fault_handler);
+    table
+        .page_fault
+        .set_handler_fn(handlers::page_fault_handler);
+    table
+        .x87_floating_point
+        .set_handler_fn(handlers::x87_floating_point_handler);
+    table
+        .alignment_check
+        .set_handler_fn(handlers::alignment_check_handler);
+    table
+        .machine_check
+        .set_handler_fn(handlers::machine_check_handler);
+    table
+        .simd_floating_point
+        .set_handler_fn(handlers::simd_floating_point_handler);
+    table
+        .virtualization
+        .set_handler_fn(handlers::virtualization_handler);
+    table
+        .security_exception
+        .set_handler_fn(handlers::security_exception_handler);
+    for (line, &entry_point) in irq::ENTRY_POINTS.iter().enumerate() {
+        table[usize::from(PIC_1_OFFSET) + line].set_handler_fn(entry_point);
+    }
+    table[usize::from(MASKED_PIC_OFFSET + 7)]
+        .set_handler_fn(handlers::masked_pic_spurious_handler::<7>);
+    table[usize::from(MASKED_PIC_OFFSET + 15)]
+        .set_handler_fn(handlers::masked_pic_spurious_handler::<15>);
+    table[apic::SPURIOUS_VECTOR as usize].set_handler_fn(handlers::apic_spurious_handler);
     table
 });
 
+/// The number of spurious interrupts raised by the PICs.
+static SPURIOUS_IRQS: AtomicU64 = AtomicU64::new(0);
+
 static PICS: Lazy<Mutex<ChainedPics>> =
     Lazy::new(|| Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) }));
 
@@ -62,17 +175,178 @@ pub fn initialize_interrupt_descriptor_table() {
     IDT.load();
 }
 
+/// Sets up the APICs if ACPI describes them, and the 8259 PICs otherwise, and registers the
+/// handlers of the kernel's own devices. Every other IRQ line stays masked until a handler is
+/// registered for it.
+///
+/// With the `force-pic` feature, the 8259 PICs are used even if there are APICs.
 pub fn initialize_interrupt_controller() {
+    // Only the cascade input of the first PIC is unmasked when the PICs are in use
+    let pic_masks = [!(1 << CASCADE_LINE), 0xff];
+    let (has_legacy_pics, pic_offset, pic_masks) = if cfg!(feature = "force-pic") {
+        (true, PIC_1_OFFSET, pic_masks)
+    } else {
+        match apic::initialize(PIC_1_OFFSET) {
+            Ok(has_legacy_pics) => (has_legacy_pics, MASKED_PIC_OFFSET, [0xff, 0xff]),
+            Err(error) => {
+                warn!("APIC unavailable ({:?}), using the 8259 PIC", error);
+                (true, PIC_1_OFFSET, pic_masks)
+            }
+        }
+    };
+    // Even when masked, the PICs can raise spurious interrupts, so they need to be remapped
+    // away from the exception vectors whenever they are present
+    if has_legacy_pics {
+        let mut pics = PICS.lock();
+        unsafe {
+            *pics = ChainedPics::new(pic_offset, pic_offset + 8);
+            pics.initialize();
+            Port::<u8>::new(PIC_1_DATA).write(pic_masks[0]);
+            Port::<u8>::new(PIC_2_DATA).write(pic_masks[1]);
+        }
+    }
+
+    for &(index, handler) in &BUILT_IN_HANDLERS {
+        register_irq(index.irq_line(), handler, 0)
+            .expect("failed to register a built-in IRQ handler");
+    }
+    interrupts::enable();
+}
+
+/// Returns which interrupt controller is in use.
+pub fn interrupt_controller() -> InterruptController {
+    match apic::local_apic() {
+        Some(_) => InterruptController::Apic,
+        None => InterruptController::Pic,
+    }
+}
+
+/// Signals the end of an interrupt on an IRQ line to the interrupt controller in use.
+pub(crate) fn end_of_interrupt(line: u8) {
+    match apic::local_apic() {
+        Some(local_apic) => local_apic.lock().end_of_interrupt(),
+        None => pic_end_of_interrupt(line),
+    }
+}
+
+/// Signals the end of an interrupt on an IRQ line to the PICs, at whichever vectors they use.
+fn pic_end_of_interrupt(line: u8) {
+    let offset = match apic::local_apic() {
+        Some(_) => MASKED_PIC_OFFSET,
+        None => PIC_1_OFFSET,
+    };
+    unsafe { PICS.lock().notify_end_of_interrupt(offset + line) };
+}
+
+/// Returns the number of spurious interrupts the PICs have raised since boot.
+pub fn spurious_irq_count() -> u64 {
+    SPURIOUS_IRQS.load(Ordering::Relaxed)
+}
+
+/// Checks whether an interrupt the PICs delivered on an IRQ line was a spurious one, which
+/// happens when a line is deasserted before the CPU acknowledges it, and counts it if so.
+/// Spurious interrupts must not get an end of interrupt, except at the first PIC for a spurious
+/// interrupt of the second one, since the first PIC did see a real interrupt on its cascade
+/// line.
+pub(crate) fn handle_spurious_irq(line: u8) -> bool {
+    if !SPURIOUS_LINES.contains(&line) {
+        return false;
+    }
+    {
+        let _pics = PICS.lock();
+        // A real interrupt is marked as in service until the end of the interrupt
+        let in_service = unsafe { read_in_service_registers() };
+        if in_service & (1 << line) != 0 {
+            return false;
+        }
+    }
+
+    SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+    if line >= 8 {
+        pic_end_of_interrupt(CASCADE_LINE);
+    }
+    true
+}
+
+/// Reads the in-service registers of both PICs, with one bit per IRQ line.
+///
+/// # Safety
+/// The caller must hold the lock on the PICs, so that no other command is sent meanwhile.
+unsafe fn read_in_service_registers() -> u16 {
+    let mut primary = Port::<u8>::new(PIC_1_COMMAND);
+    let mut secondary = Port::<u8>::new(PIC_2_COMMAND);
     unsafe {
-        PICS.lock().initialize();
+        primary.write(READ_IN_SERVICE_REGISTER);
+        secondary.write(READ_IN_SERVICE_REGISTER);
+        u16::from(primary.read()) | u16::from(secondary.read()) << 8
     }
-    x86_64::instructions::interrupts::enable();
+}
+
+/// Masks or unmasks an IRQ line at the interrupt controller in use.
+///
+/// Returns `false` if the interrupt controller has no input for the line.
+fn set_line_masked(line: u8, masked: bool) -> bool {
+    if apic::local_apic().is_some() {
+        return apic::set_isa_irq_masked(line, masked);
+    }
+    // The second PIC is wired to the cascade input, so it has no line of its own
+    if line == CASCADE_LINE {
+        return false;
+    }
+    let mut port = Port::<u8>::new(if line < 8 { PIC_1_DATA } else { PIC_2_DATA });
+    unsafe {
+        let mask = port.read();
+        let bit = 1 << (line % 8);
+        port.write(if masked { mask | bit } else { mask & !bit });
+    }
+    true
+}
+
+/// Returns whether an IRQ line is masked at the interrupt controller in use. Lines that the
+/// interrupt controller has no input for count as masked.
+fn is_line_masked(line: u8) -> bool {
+    if apic::local_apic().is_some() {
+        return apic::is_isa_irq_masked(line);
+    }
+    let mut port = Port::<u8>::new(if line < 8 { PIC_1_DATA } else { PIC_2_DATA });
+    unsafe { port.read() & (1 << (line % 8)) != 0 }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test_case]
     fn test_breakpoint_exception() {
         x86_64::instructions::interrupts::int3();
     }
+
+    #[test_case]
+    fn test_spurious_irqs_are_counted() {
+        let spurious_irqs = spurious_irq_count();
+        interrupts::without_interrupts(|| {
+            // Nothing is in service here, so IRQ 7 and IRQ 15 can only be spurious
+            assert!(handle_spurious_irq(7));
+            assert!(handle_spurious_irq(15));
+            assert!(!handle_spurious_irq(1));
+        });
+        assert_eq!(spurious_irq_count(), spurious_irqs + 2);
+
+        // A spurious IRQ 7 of the masked PICs only arrives while the APICs are in use, but the
+        // handler is installed either way
+        let vector = MASKED_PIC_OFFSET + 7;
+        let count = interrupt_count(vector);
+        unsafe { core::arch::asm!("int 0xe7", options(nomem, nostack)) };
+        assert_eq!(interrupt_count(vector), count + 1);
+        assert_eq!(spurious_irq_count(), spurious_irqs + 3);
+    }
+
+    #[test_case]
+    fn test_no_irq_is_in_service_outside_of_handlers() {
+        let in_service = interrupts::without_interrupts(|| {
+            let _pics = PICS.lock();
+            unsafe { read_in_service_registers() }
+        });
+        assert_eq!(in_service, 0);
+    }
 }
@@ -0,0 +1,265 @@
+//! The local APIC and the I/O APICs, which replace the 8259 PICs when the MADT describes them.
+
+use alloc::vec::Vec;
+
+use conquer_once::spin::OnceCell;
+use x86_64::registers::model_specific::Msr;
+
+use crate::{
+    acpi::{self, InterruptRouting},
+    cpu,
+    memory::{map_mmio, MmioRegion, VmallocError},
+    sync::Mutex,
+};
+
+/// The model specific register holding the local APIC's base address and global enable bit.
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+const LOCAL_APIC_SIZE: usize = 0x400;
+const LOCAL_APIC_ID: usize = 0x20;
+const LOCAL_APIC_TASK_PRIORITY: usize = 0x80;
+const LOCAL_APIC_END_OF_INTERRUPT: usize = 0xb0;
+const LOCAL_APIC_SPURIOUS_VECTOR: usize = 0xf0;
+const LOCAL_APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+/// The vector of the local APIC's spurious interrupts, which don't need an end of interrupt.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// The I/O APIC is accessed indirectly, by selecting a register and then using the window.
+const IO_APIC_SIZE: usize = 0x20;
+const IO_APIC_REGISTER_SELECT: usize = 0x00;
+const IO_APIC_WINDOW: usize = 0x10;
+const IO_APIC_VERSION: u32 = 0x01;
+/// Each redirection entry takes two registers, starting at this one.
+const IO_APIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+/// The number of legacy ISA interrupt lines.
+const ISA_IRQS: u8 = 16;
+
+static LOCAL_APIC: OnceCell<Mutex<LocalApic>> = OnceCell::uninit();
+static IO_APICS: OnceCell<Mutex<Vec<IoApic>>> = OnceCell::uninit();
+/// The I/O APIC and input each ISA interrupt line is connected to, by index into `IO_APICS`.
+static ISA_ROUTES: OnceCell<[Option<(usize, InterruptRouting)>; ISA_IRQS as usize]> =
+    OnceCell::uninit();
+
+/// Why the APICs couldn't be set up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError {
+    /// CPUID doesn't report a local APIC.
+    NotSupported,
+    /// There is no valid MADT, or it describes no I/O APIC.
+    NotDescribed,
+    Mapping(VmallocError),
+}
+
+impl From<VmallocError> for ApicError {
+    fn from(error: VmallocError) -> Self {
+        Self::Mapping(error)
+    }
+}
+
+/// The current processor's local APIC, which receives the interrupts routed by the I/O APICs.
+#[derive(Debug)]
+pub struct LocalApic {
+    registers: MmioRegion,
+}
+
+impl LocalApic {
+    pub fn id(&self) -> u8 {
+        (self.registers.read::<u32>(LOCAL_APIC_ID) >> 24) as u8
+    }
+
+    /// Returns whether the local APIC accepts interrupts.
+    pub fn is_enabled(&self) -> bool {
+        self.registers.read::<u32>(LOCAL_APIC_SPURIOUS_VECTOR) & LOCAL_APIC_SOFTWARE_ENABLE != 0
+    }
+
+    /// Signals that the interrupt currently being handled is done.
+    pub fn end_of_interrupt(&mut self) {
+        self.registers.write::<u32>(LOCAL_APIC_END_OF_INTERRUPT, 0);
+    }
+
+    fn enable(&mut self) {
+        // Accept interrupts of every priority
+        self.registers.write::<u32>(LOCAL_APIC_TASK_PRIORITY, 0);
+        self.registers.write(
+            LOCAL_APIC_SPURIOUS_VECTOR,
+            LOCAL_APIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR),
+        );
+    }
+}
+
+/// An I/O APIC, which forwards each of its input pins to a vector of a local APIC.
+#[derive(Debug)]
+pub struct IoApic {
+    registers: MmioRegion,
+    gsi_base: u32,
+    inputs: u32,
+}
+
+impl IoApic {
+    /// Returns whether one of the inputs is connected to the global system interrupt.
+    pub fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.inputs).contains(&gsi)
+    }
+
+    /// Routes a global system interrupt to a vector of the local APIC with the given ID. The
+    /// input stays masked.
+    fn route(&mut self, routing: InterruptRouting, vector: u8, destination: u8) {
+        let mut low = u32::from(vector) | REDIRECTION_MASKED;
+        if routing.active_low {
+            low |= REDIRECTION_ACTIVE_LOW;
+        }
+        if routing.level_triggered {
+            low |= REDIRECTION_LEVEL_TRIGGERED;
+        }
+        let register = self.redirection_register(routing.gsi);
+        // Mask the entry while it is only partially written
+        self.write(register, REDIRECTION_MASKED);
+        self.write(register + 1, u32::from(destination) << 24);
+        self.write(register, low);
+    }
+
+    fn set_masked(&mut self, gsi: u32, masked: bool) {
+        let register = self.redirection_register(gsi);
+        let low = self.read(register);
+        if masked {
+            self.write(register, low | REDIRECTION_MASKED);
+        } else {
+            self.write(register, low & !REDIRECTION_MASKED);
+        }
+    }
+
+    fn is_masked(&mut self, gsi: u32) -> bool {
+        let register = self.redirection_register(gsi);
+        self.read(register) & REDIRECTION_MASKED != 0
+    }
+
+    fn redirection_register(&self, gsi: u32) -> u32 {
+        IO_APIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base)
+    }
+
+    fn mask_all(&mut self) {
+        for input in 0..self.inputs {
+            self.write(IO_APIC_REDIRECTION_TABLE + 2 * input, REDIRECTION_MASKED);
+        }
+    }
+
+    fn read(&mut self, register: u32) -> u32 {
+        self.registers.write(IO_APIC_REGISTER_SELECT, register);
+        self.registers.read(IO_APIC_WINDOW)
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        self.registers.write(IO_APIC_REGISTER_SELECT, register);
+        self.registers.write(IO_APIC_WINDOW, value);
+    }
+}
+
+/// Returns the local APIC, if the APICs are in use.
+pub fn local_apic() -> Option<&'static Mutex<LocalApic>> {
+    LOCAL_APIC.get()
+}
+
+/// Enables the local APIC and routes each legacy ISA interrupt line to the vector `offset + irq`.
+/// Every I/O APIC input starts out masked.
+///
+/// Returns whether the MADT reports legacy 8259 PICs, which the caller is responsible for
+/// masking.
+pub(crate) fn initialize(offset: u8) -> Result<bool, ApicError> {
+    if !cpu::has_apic() {
+        return Err(ApicError::NotSupported);
+    }
+    let madt = acpi::madt()
+        .filter(|madt| !madt.io_apics.is_empty())
+        .ok_or(ApicError::NotDescribed)?;
+
+    let mut io_apics = Vec::with_capacity(madt.io_apics.len());
+    for info in &madt.io_apics {
+        let mut io_apic = IoApic {
+            registers: unsafe { map_mmio(info.address, IO_APIC_SIZE)? },
+            gsi_base: info.gsi_base,
+            inputs: 0,
+        };
+        io_apic.inputs = ((io_apic.read(IO_APIC_VERSION) >> 16) & 0xff) + 1;
+        io_apic.mask_all();
+        io_apics.push(io_apic);
+    }
+
+    let mut local_apic = LocalApic {
+        registers: unsafe { map_mmio(madt.local_apic_address, LOCAL_APIC_SIZE)? },
+    };
+    let mut base = Msr::new(IA32_APIC_BASE);
+    unsafe { base.write(base.read() | APIC_GLOBAL_ENABLE) };
+    local_apic.enable();
+
+    let mut routes = [None; ISA_IRQS as usize];
+    for (irq, route) in (0..ISA_IRQS).zip(routes.iter_mut()) {
+        *route = madt.isa_routing(irq).and_then(|routing| {
+            let index = io_apics
+                .iter()
+                .position(|io_apic| io_apic.handles(routing.gsi))?;
+            io_apics[index].route(routing, offset + irq, local_apic.id());
+            Some((index, routing))
+        });
+    }
+
+    LOCAL_APIC.init_once(|| Mutex::new(local_apic));
+    IO_APICS.init_once(|| Mutex::new(io_apics));
+    ISA_ROUTES.init_once(|| routes);
+    Ok(madt.has_legacy_pics)
+}
+
+/// Masks or unmasks the I/O APIC input of a legacy ISA interrupt line.
+///
+/// Returns `false` if the line isn't connected to any I/O APIC input.
+pub(crate) fn set_isa_irq_masked(irq: u8, masked: bool) -> bool {
+    let route = ISA_ROUTES
+        .get()
+        .and_then(|routes| routes.get(usize::from(irq)).copied().flatten());
+    match (route, IO_APICS.get()) {
+        (Some((index, routing)), Some(io_apics)) => {
+            io_apics.lock()[index].set_masked(routing.gsi, masked);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Returns whether the I/O APIC input of a legacy ISA interrupt line is masked. Lines that
+/// aren't connected to any input count as masked.
+pub(crate) fn is_isa_irq_masked(irq: u8) -> bool {
+    let route = ISA_ROUTES
+        .get()
+        .and_then(|routes| routes.get(usize::from(irq)).copied().flatten());
+    match (route, IO_APICS.get()) {
+        (Some((index, routing)), Some(io_apics)) => io_apics.lock()[index].is_masked(routing.gsi),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(not(feature = "force-pic"))]
+    #[test_case]
+    fn test_local_apic_is_enabled() {
+        let local_apic = local_apic().expect("the APICs aren't in use");
+        assert!(local_apic.lock().is_enabled());
+        assert!(IO_APICS
+            .get()
+            .is_some_and(|io_apics| io_apics.lock().iter().any(|io_apic| io_apic.handles(2))));
+    }
+
+    #[test_case]
+    fn test_timer_interrupt_is_delivered() {
+        // This never returns if no interrupt arrives
+        x86_64::instructions::hlt();
+    }
+}
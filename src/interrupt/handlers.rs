@@ -1,66 +1,367 @@
 //! Handler functions for CPU interrupts.
 
+use core::fmt;
+
 use log::error;
 use x86_64::{
-    registers::control::Cr2,
+    registers::control::{Cr0, Cr2, Cr3, Cr4},
     structures::idt::{InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
 };
 
 use crate::{
-    interrupt::{InterruptIndex, PICS},
+    interrupt::{self, apic, statistics, MASKED_PIC_OFFSET},
+    memory,
     task::scancode_queue::ScancodeQueue,
 };
 
+/// The error code an exception pushed, decoded according to the exception.
+#[derive(Debug, Clone, Copy)]
+enum ErrorCode {
+    Plain(u64),
+    /// Refers to the segment selector or IDT entry that caused the exception.
+    Selector(u64),
+    PageFault {
+        error_code: PageFaultErrorCode,
+        address: VirtAddr,
+    },
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Plain(error_code) => write!(f, "{:#x}", error_code),
+            Self::Selector(0) => write!(f, "0x0 (no selector)"),
+            Self::Selector(error_code) => {
+                // The table bits are 0b01 or 0b11 for the IDT
+                let table = match (error_code >> 1) & 0x3 {
+                    0b00 => "GDT",
+                    0b10 => "LDT",
+                    _ => "IDT",
+                };
+                write!(
+                    f,
+                    "{:#x} (index {} in the {}{})",
+                    error_code,
+                    (error_code >> 3) & 0x1fff,
+                    table,
+                    if error_code & 1 != 0 {
+                        ", external"
+                    } else {
+                        ""
+                    }
+                )
+            }
+            Self::PageFault {
+                error_code,
+                address,
+            } => {
+                let area = memory::area_containing(address).map_or("none", |area| area.kind.name());
+                write!(
+                    f,
+                    "{:?}\naccessed address: {:?} (area: {})",
+                    error_code, address, area
+                )
+            }
+        }
+    }
+}
+
+/// The exceptions that have a handler, by vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum Exception {
+    DivideError = 0,
+    Debug = 1,
+    NonMaskableInterrupt = 2,
+    Breakpoint = 3,
+    Overflow = 4,
+    BoundRangeExceeded = 5,
+    InvalidOpcode = 6,
+    DeviceNotAvailable = 7,
+    DoubleFault = 8,
+    InvalidTss = 10,
+    SegmentNotPresent = 11,
+    StackSegmentFault = 12,
+    GeneralProtectionFault = 13,
+    PageFault = 14,
+    X87FloatingPoint = 16,
+    AlignmentCheck = 17,
+    MachineCheck = 18,
+    SimdFloatingPoint = 19,
+    Virtualization = 20,
+    Security = 30,
+}
+
+impl Exception {
+    pub fn vector(self) -> u8 {
+        self as u8
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::DivideError => "divide error",
+            Self::Debug => "debug",
+            Self::NonMaskableInterrupt => "non-maskable interrupt",
+            Self::Breakpoint => "breakpoint",
+            Self::Overflow => "overflow",
+            Self::BoundRangeExceeded => "bound range exceeded",
+            Self::InvalidOpcode => "invalid opcode",
+            Self::DeviceNotAvailable => "device not available",
+            Self::DoubleFault => "double fault",
+            Self::InvalidTss => "invalid TSS",
+            Self::SegmentNotPresent => "segment not present",
+            Self::StackSegmentFault => "stack segment fault",
+            Self::GeneralProtectionFault => "general protection fault",
+            Self::PageFault => "page fault",
+            Self::X87FloatingPoint => "x87 floating point exception",
+            Self::AlignmentCheck => "alignment check",
+            Self::MachineCheck => "machine check",
+            Self::SimdFloatingPoint => "SIMD floating point exception",
+            Self::Virtualization => "virtualization exception",
+            Self::Security => "security exception",
+        }
+    }
+}
+
+/// Everything known about an exception the kernel can't recover from.
+struct ExceptionReport<'a> {
+    exception: Exception,
+    error_code: Option<ErrorCode>,
+    stack_frame: &'a InterruptStackFrame,
+}
+
+impl fmt::Display for ExceptionReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "EXCEPTION: {} ({})",
+            self.exception.name(),
+            statistics::exception_mnemonic(self.exception.vector())
+        )?;
+        if let Some(error_code) = self.error_code {
+            writeln!(f, "error code: {}", error_code)?;
+        }
+        writeln!(
+            f,
+            "faulting instruction: {:?}",
+            self.stack_frame.instruction_pointer
+        )?;
+        writeln!(f, "{:#?}", self.stack_frame)?;
+        write!(
+            f,
+            "cr0: {:?}\ncr2: {:?}\ncr3: {:?}\ncr4: {:?}",
+            Cr0::read(),
+            Cr2::read(),
+            Cr3::read(),
+            Cr4::read()
+        )
+    }
+}
+
+/// Reports an exception the kernel can't recover from, by panicking with the exception, its
+/// decoded error code, and the CPU state at the time.
+fn fatal_exception(
+    exception: Exception,
+    error_code: Option<ErrorCode>,
+    stack_frame: &InterruptStackFrame,
+) -> ! {
+    panic!(
+        "{}",
+        ExceptionReport {
+            exception,
+            error_code,
+            stack_frame,
+        }
+    );
+}
+
+pub extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    statistics::record(Exception::DivideError.vector());
+    fatal_exception(Exception::DivideError, None, &stack_frame);
+}
+
+/// Debug exception handler. Debug exceptions are only raised when debugging is set up, so this
+/// just logs the exception and continues.
+pub extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    statistics::record(Exception::Debug.vector());
+    error!("EXCEPTION: debug\n{:#?}", stack_frame);
+}
+
+/// Non-maskable interrupt handler. These usually signal hardware errors, which we can't do
+/// anything about yet, so this just logs the interrupt and continues.
+pub extern "x86-interrupt" fn non_maskable_interrupt_handler(stack_frame: InterruptStackFrame) {
+    statistics::record(Exception::NonMaskableInterrupt.vector());
+    error!("EXCEPTION: non-maskable interrupt\n{:#?}", stack_frame);
+}
+
 /// Breakpoint exception handler. Currently, this just logs the exception and continues.
 pub extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    statistics::record(Exception::Breakpoint.vector());
     error!("EXCEPTION: breakpoint\n{:#?}", stack_frame);
 }
 
-/// Page fault handler. I haven't implemented advanced page management yet (e.g. swapping),
-/// so currently this causes the OS to halt.
+pub extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
+    statistics::record(Exception::Overflow.vector());
+    fatal_exception(Exception::Overflow, None, &stack_frame);
+}
+
+pub extern "x86-interrupt" fn bound_range_exceeded_handler(stack_frame: InterruptStackFrame) {
+    statistics::record(Exception::BoundRangeExceeded.vector());
+    fatal_exception(Exception::BoundRangeExceeded, None, &stack_frame);
+}
+
+pub extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    statistics::record(Exception::InvalidOpcode.vector());
+    fatal_exception(Exception::InvalidOpcode, None, &stack_frame);
+}
+
+pub extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+    statistics::record(Exception::DeviceNotAvailable.vector());
+    fatal_exception(Exception::DeviceNotAvailable, None, &stack_frame);
+}
+
+pub extern "x86-interrupt" fn invalid_tss_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    statistics::record(Exception::InvalidTss.vector());
+    let error_code = Some(ErrorCode::Selector(error_code));
+    fatal_exception(Exception::InvalidTss, error_code, &stack_frame);
+}
+
+pub extern "x86-interrupt" fn segment_not_present_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    statistics::record(Exception::SegmentNotPresent.vector());
+    let error_code = Some(ErrorCode::Selector(error_code));
+    fatal_exception(Exception::SegmentNotPresent, error_code, &stack_frame);
+}
+
+pub extern "x86-interrupt" fn stack_segment_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    statistics::record(Exception::StackSegmentFault.vector());
+    let error_code = Some(ErrorCode::Selector(error_code));
+    fatal_exception(Exception::StackSegmentFault, error_code, &stack_frame);
+}
+
+pub extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    statistics::record(Exception::GeneralProtectionFault.vector());
+    let error_code = Some(ErrorCode::Selector(error_code));
+    fatal_exception(Exception::GeneralProtectionFault, error_code, &stack_frame);
+}
+
+/// Page fault handler. Faults on lazily-backed regions are resolved by mapping a fresh frame, and
+/// writes to copy-on-write pages by copying the shared frame. I haven't implemented other page
+/// management yet (e.g. swapping), so any other fault is fatal.
 pub extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
-    error!(
-        "EXCEPTION: page fault\naccessed address: {:?}\nerror code: {:?}\n{:#?}",
-        Cr2::read(),
+    statistics::record(Exception::PageFault.vector());
+    let address = Cr2::read();
+    let handled = if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+            && memory::handle_copy_on_write_fault(address)
+    } else {
+        memory::handle_page_fault(address)
+    };
+    if handled {
+        return;
+    }
+
+    let error_code = Some(ErrorCode::PageFault {
         error_code,
-        stack_frame
-    );
-    crate::halt();
+        address,
+    });
+    fatal_exception(Exception::PageFault, error_code, &stack_frame);
 }
 
-/// Double fault handler. Currently, this just logs the exception and halts.
+/// Double fault handler. This runs on its own stack, so that even a stack overflow is reported.
 pub extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
-    _error_code: u64,
+    error_code: u64,
 ) -> ! {
-    error!("EXCEPTION: double fault\n{:#?}", stack_frame);
-    crate::halt();
+    statistics::record(Exception::DoubleFault.vector());
+    let error_code = Some(ErrorCode::Plain(error_code));
+    fatal_exception(Exception::DoubleFault, error_code, &stack_frame);
+}
+
+pub extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+    statistics::record(Exception::X87FloatingPoint.vector());
+    fatal_exception(Exception::X87FloatingPoint, None, &stack_frame);
+}
+
+pub extern "x86-interrupt" fn alignment_check_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    statistics::record(Exception::AlignmentCheck.vector());
+    let error_code = Some(ErrorCode::Plain(error_code));
+    fatal_exception(Exception::AlignmentCheck, error_code, &stack_frame);
+}
+
+pub extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    statistics::record(Exception::MachineCheck.vector());
+    fatal_exception(Exception::MachineCheck, None, &stack_frame);
+}
+
+pub extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    statistics::record(Exception::SimdFloatingPoint.vector());
+    fatal_exception(Exception::SimdFloatingPoint, None, &stack_frame);
+}
+
+pub extern "x86-interrupt" fn virtualization_handler(stack_frame: InterruptStackFrame) {
+    statistics::record(Exception::Virtualization.vector());
+    fatal_exception(Exception::Virtualization, None, &stack_frame);
+}
+
+pub extern "x86-interrupt" fn security_exception_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    statistics::record(Exception::Security.vector());
+    let error_code = Some(ErrorCode::Plain(error_code));
+    fatal_exception(Exception::Security, error_code, &stack_frame);
 }
 
-/// Timer handler. This doesn't do anything except signal the end of the interrupt.
-pub extern "x86-interrupt" fn timer_handler(_stack_frame: InterruptStackFrame) {
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer as u8);
+/// Spurious interrupt handler for the local APIC. These interrupts must not be acknowledged, so
+/// there is nothing to do.
+pub extern "x86-interrupt" fn apic_spurious_handler(_stack_frame: InterruptStackFrame) {
+    statistics::record(apic::SPURIOUS_VECTOR);
+}
+
+/// Handler of the spurious interrupts of the PICs while the APICs are in use. The PICs are masked
+/// then, so these are only counted, and the local APIC doesn't get an end of interrupt since it
+/// didn't deliver them.
+pub extern "x86-interrupt" fn masked_pic_spurious_handler<const LINE: u8>(
+    _stack_frame: InterruptStackFrame,
+) {
+    statistics::record(MASKED_PIC_OFFSET + LINE);
+    if !interrupt::handle_spurious_irq(LINE) {
+        // A masked line can't raise a real interrupt, but don't leave one in service anyway
+        interrupt::pic_end_of_interrupt(LINE);
     }
 }
 
-/// Keyboard handler. This adds the scancode of any key pressed onto the global scancode
+/// Timer IRQ handler. The timer isn't used for anything yet, but its interrupts keep `hlt` from
+/// waiting forever.
+pub fn timer_handler(_context: usize) {}
+
+/// Keyboard IRQ handler. This adds the scancode of any key pressed onto the global scancode
 /// queue, which is later read by an asynchronous task.
-pub extern "x86-interrupt" fn keyboard_handler(_stack_frame: InterruptStackFrame) {
+pub fn keyboard_handler(_context: usize) {
     // TODO: Make sure timeout doesn't cause spurious panics
     ScancodeQueue::add_scancode(unsafe { ps2::Controller::new().read_data().unwrap() });
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard as u8);
-    }
 }
 
-pub extern "x86-interrupt" fn mouse_handler(_stack_frame: InterruptStackFrame) {
+pub fn mouse_handler(_context: usize) {
     let mut controller = unsafe { ps2::Controller::new() };
     // TODO: Two interrupts seem to be triggered on each event, but the second one doesn't have
     //       a data packet available to read. What's going on?
@@ -69,9 +370,4 @@ pub extern "x86-interrupt" fn mouse_handler(_stack_frame: InterruptStackFrame) {
     } else {
         // TODO: Handle events missing packets
     }
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Mouse as u8);
-    }
 }
@@ -0,0 +1,211 @@
+//! Registration of device interrupt handlers for the legacy IRQ lines.
+
+use x86_64::{instructions::interrupts, structures::idt::InterruptStackFrame};
+
+use crate::{
+    interrupt::{self, statistics, InterruptController, PIC_1_OFFSET},
+    sync::Mutex,
+};
+
+/// The number of IRQ lines, which use the vectors from `PIC_1_OFFSET` on.
+pub const IRQ_LINES: u8 = 16;
+/// The number of handlers that can share a single IRQ line.
+pub const MAX_HANDLERS_PER_LINE: usize = 4;
+
+/// A handler for a device interrupt, which is passed the context it was registered with. Handlers
+/// of a shared line are all called on every interrupt, so each has to check whether its device
+/// needs attention, e.g. by using the context to find the device.
+pub type IrqHandler = fn(usize);
+
+/// The handlers of a single line, along with their context.
+type LineHandlers = [Option<(IrqHandler, usize)>; MAX_HANDLERS_PER_LINE];
+
+/// The handlers of each line. This is a fixed-size table so that dispatching never needs the
+/// heap, and is only locked with interrupts disabled.
+static HANDLERS: Mutex<[LineHandlers; IRQ_LINES as usize]> =
+    Mutex::new([[None; MAX_HANDLERS_PER_LINE]; IRQ_LINES as usize]);
+
+/// The entry points of the IRQ lines, in order, which dispatch to the registered handlers.
+pub(crate) const ENTRY_POINTS: [extern "x86-interrupt" fn(InterruptStackFrame);
+    IRQ_LINES as usize] = [
+    irq_entry::<0>,
+    irq_entry::<1>,
+    irq_entry::<2>,
+    irq_entry::<3>,
+    irq_entry::<4>,
+    irq_entry::<5>,
+    irq_entry::<6>,
+    irq_entry::<7>,
+    irq_entry::<8>,
+    irq_entry::<9>,
+    irq_entry::<10>,
+    irq_entry::<11>,
+    irq_entry::<12>,
+    irq_entry::<13>,
+    irq_entry::<14>,
+    irq_entry::<15>,
+];
+
+/// Why an IRQ handler couldn't be registered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    InvalidLine(u8),
+    /// The line already has `MAX_HANDLERS_PER_LINE` handlers.
+    LineFull(u8),
+    /// The interrupt controller has no input for the line.
+    Unroutable(u8),
+}
+
+/// Identifies a registered handler, so that it can be unregistered again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqRegistration {
+    line: u8,
+    slot: usize,
+}
+
+impl IrqRegistration {
+    pub fn line(&self) -> u8 {
+        self.line
+    }
+}
+
+/// Adds a handler to an IRQ line, and unmasks the line if it is the first one. The handler is
+/// called with `context`, which can tell apart the devices sharing a line. The end of the
+/// interrupt is signaled after all handlers of the line have run.
+pub fn register_irq(
+    line: u8,
+    handler: IrqHandler,
+    context: usize,
+) -> Result<IrqRegistration, IrqError> {
+    if line >= IRQ_LINES {
+        return Err(IrqError::InvalidLine(line));
+    }
+    interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let line_handlers = &mut handlers[usize::from(line)];
+        let slot = line_handlers
+            .iter()
+            .position(Option::is_none)
+            .ok_or(IrqError::LineFull(line))?;
+        if line_handlers.iter().all(Option::is_none) && !interrupt::set_line_masked(line, false) {
+            return Err(IrqError::Unroutable(line));
+        }
+        line_handlers[slot] = Some((handler, context));
+        Ok(IrqRegistration { line, slot })
+    })
+}
+
+/// Removes a registered handler, and masks its line if no handlers are left.
+pub fn unregister_irq(registration: IrqRegistration) {
+    interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let line_handlers = &mut handlers[usize::from(registration.line)];
+        line_handlers[registration.slot] = None;
+        if line_handlers.iter().all(Option::is_none) {
+            interrupt::set_line_masked(registration.line, true);
+        }
+    });
+}
+
+/// Returns the number of handlers registered for a line.
+pub fn handler_count(line: u8) -> usize {
+    interrupts::without_interrupts(|| {
+        HANDLERS
+            .lock()
+            .get(usize::from(line))
+            .map_or(0, |line_handlers| line_handlers.iter().flatten().count())
+    })
+}
+
+/// Returns whether a line is masked at the interrupt controller in use. Lines without a
+/// handler are always masked, except for the cascade line of the 8259 PICs.
+pub fn is_irq_masked(line: u8) -> bool {
+    line >= IRQ_LINES || interrupts::without_interrupts(|| interrupt::is_line_masked(line))
+}
+
+/// Calls every handler of the line, then signals the end of the interrupt. Spurious interrupts
+/// of the PICs are only counted.
+extern "x86-interrupt" fn irq_entry<const LINE: u8>(_stack_frame: InterruptStackFrame) {
+    statistics::record(PIC_1_OFFSET + LINE);
+    // With the APICs, the spurious interrupts of the PICs arrive at other vectors
+    if interrupt::interrupt_controller() == InterruptController::Pic
+        && interrupt::handle_spurious_irq(LINE)
+    {
+        return;
+    }
+    // Copy the handlers, so that they can register or unregister handlers themselves
+    let handlers = HANDLERS.lock()[usize::from(LINE)];
+    for &(handler, context) in handlers.iter().flatten() {
+        handler(context);
+    }
+    interrupt::end_of_interrupt(LINE);
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::interrupt::InterruptIndex;
+
+    static FIRST_CALLS: AtomicUsize = AtomicUsize::new(0);
+    static FIRST_CONTEXT: AtomicUsize = AtomicUsize::new(0);
+    static SECOND_CALLS: AtomicUsize = AtomicUsize::new(0);
+    static SECOND_CONTEXT: AtomicUsize = AtomicUsize::new(0);
+
+    fn first_handler(context: usize) {
+        FIRST_CONTEXT.store(context, Ordering::Relaxed);
+        FIRST_CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn second_handler(context: usize) {
+        SECOND_CONTEXT.store(context, Ordering::Relaxed);
+        SECOND_CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn test_shared_irq_handlers_are_chained() {
+        let line = InterruptIndex::Timer.irq_line();
+        let handlers = handler_count(line);
+        let first = register_irq(line, first_handler, 1).unwrap();
+        let second = register_irq(line, second_handler, 2).unwrap();
+        assert_eq!(handler_count(line), handlers + 2);
+
+        // Wait for a timer interrupt, which calls both handlers, the first one first
+        let first_calls = FIRST_CALLS.load(Ordering::Relaxed);
+        let second_calls = SECOND_CALLS.load(Ordering::Relaxed);
+        while FIRST_CALLS.load(Ordering::Relaxed) == first_calls {
+            x86_64::instructions::hlt();
+        }
+        unregister_irq(first);
+        unregister_irq(second);
+        assert!(SECOND_CALLS.load(Ordering::Relaxed) > second_calls);
+        assert_eq!(FIRST_CONTEXT.load(Ordering::Relaxed), 1);
+        assert_eq!(SECOND_CONTEXT.load(Ordering::Relaxed), 2);
+
+        // The built-in timer handler keeps the line unmasked
+        assert_eq!(handler_count(line), handlers);
+        assert!(!is_irq_masked(line));
+        assert_eq!(
+            register_irq(IRQ_LINES, first_handler, 0),
+            Err(IrqError::InvalidLine(16))
+        );
+    }
+
+    #[test_case]
+    fn test_line_is_masked_without_handlers() {
+        let line = InterruptIndex::Open1.irq_line();
+        assert_eq!(handler_count(line), 0);
+        assert!(is_irq_masked(line));
+        // Keep a device on the line from interrupting while it is unmasked
+        interrupts::without_interrupts(|| {
+            let first = register_irq(line, first_handler, 0).unwrap();
+            let second = register_irq(line, second_handler, 0).unwrap();
+            assert!(!is_irq_masked(line));
+            unregister_irq(first);
+            assert!(!is_irq_masked(line));
+            unregister_irq(second);
+        });
+        assert!(is_irq_masked(line));
+    }
+}
@@ -0,0 +1,224 @@
+//! Counters of how often each interrupt vector fires.
+
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use log::info;
+
+use crate::interrupt::{apic, spurious_irq_count, IRQ_LINES, MASKED_PIC_OFFSET, PIC_1_OFFSET};
+
+const VECTORS: usize = 256;
+
+/// The mnemonics of the exceptions, by vector. Reserved vectors are empty.
+const EXCEPTION_MNEMONICS: [&str; 32] = [
+    "#DE", "#DB", "NMI", "#BP", "#OF", "#BR", "#UD", "#NM", "#DF", "", "#TS", "#NP", "#SS", "#GP",
+    "#PF", "", "#MF", "#AC", "#MC", "#XM", "#VE", "#CP", "", "", "", "", "", "", "#HV", "#VC",
+    "#SX", "",
+];
+
+/// The devices conventionally connected to each legacy IRQ line.
+const IRQ_DEVICES: [&str; IRQ_LINES as usize] = [
+    "timer",
+    "keyboard",
+    "cascade",
+    "serial 2/4",
+    "serial 1/3",
+    "sound, parallel 2/3",
+    "floppy disk",
+    "parallel 1",
+    "real time clock",
+    "ACPI",
+    "open",
+    "open",
+    "mouse",
+    "coprocessor",
+    "primary ATA",
+    "secondary ATA",
+];
+
+/// Returns the mnemonic of the exception with the given vector, e.g. `#PF`.
+pub(crate) fn exception_mnemonic(vector: u8) -> &'static str {
+    EXCEPTION_MNEMONICS
+        .get(usize::from(vector))
+        .copied()
+        .unwrap_or("")
+}
+
+static COUNTS: [AtomicU64; VECTORS] = [const { AtomicU64::new(0) }; VECTORS];
+
+/// Counts an interrupt on the given vector. Every exception handler calls this first, and the
+/// IRQ handlers are counted by the entry point of their line.
+pub(crate) fn record(vector: u8) {
+    COUNTS[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns how often the given vector has fired since boot.
+pub fn interrupt_count(vector: u8) -> u64 {
+    COUNTS[usize::from(vector)].load(Ordering::Relaxed)
+}
+
+/// A snapshot of the interrupt counters.
+///
+/// The `Display` implementation prints a table in the style of Linux's `/proc/interrupts`, with a
+/// row for each vector that has fired.
+#[derive(Debug, Clone)]
+pub struct InterruptStatistics {
+    counts: [u64; VECTORS],
+    spurious_irqs: u64,
+}
+
+impl InterruptStatistics {
+    /// Returns how often the given vector had fired.
+    pub fn count(&self, vector: u8) -> u64 {
+        self.counts[usize::from(vector)]
+    }
+
+    /// Returns the number of spurious interrupts the PICs had raised. These are also counted on
+    /// the vectors of IRQ 7 and IRQ 15, which are at `MASKED_PIC_OFFSET` while the APICs are in
+    /// use.
+    pub fn spurious_irqs(&self) -> u64 {
+        self.spurious_irqs
+    }
+
+    /// Returns the number of interrupts of all vectors.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Returns the interrupts that fired between an earlier snapshot and this one, which helps
+    /// to spot interrupt storms.
+    pub fn since(&self, earlier: &InterruptStatistics) -> InterruptStatistics {
+        let mut counts = self.counts;
+        for (count, earlier) in counts.iter_mut().zip(earlier.counts.iter()) {
+            *count = count.saturating_sub(*earlier);
+        }
+        InterruptStatistics {
+            counts,
+            spurious_irqs: self.spurious_irqs.saturating_sub(earlier.spurious_irqs),
+        }
+    }
+}
+
+impl fmt::Display for InterruptStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:>6} {:>12}  source", "vector", "count")?;
+        for (vector, &count) in self.counts.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            write!(f, "{:>6} {:>12}  ", vector, count)?;
+            let irq_line = vector.wrapping_sub(PIC_1_OFFSET.into());
+            let masked_pic_line = vector.wrapping_sub(MASKED_PIC_OFFSET.into());
+            match vector {
+                0..=31 => writeln!(f, "exception {}", EXCEPTION_MNEMONICS[vector])?,
+                _ if irq_line < IRQ_DEVICES.len() => {
+                    writeln!(f, "IRQ {} ({})", irq_line, IRQ_DEVICES[irq_line])?
+                }
+                _ if masked_pic_line < IRQ_DEVICES.len() => {
+                    writeln!(f, "masked PIC IRQ {}", masked_pic_line)?
+                }
+                _ if vector == apic::SPURIOUS_VECTOR.into() => writeln!(f, "APIC spurious")?,
+                _ => writeln!(f, "other")?,
+            }
+        }
+        write!(
+            f,
+            "{:>6} {:>12}  spurious PIC interrupts",
+            "SPU", self.spurious_irqs
+        )
+    }
+}
+
+/// Takes a snapshot of the interrupt counters.
+pub fn interrupt_statistics() -> InterruptStatistics {
+    let mut counts = [0; VECTORS];
+    for (count, counter) in counts.iter_mut().zip(COUNTS.iter()) {
+        *count = counter.load(Ordering::Relaxed);
+    }
+    InterruptStatistics {
+        counts,
+        spurious_irqs: spurious_irq_count(),
+    }
+}
+
+/// Logs the interrupt counters as a table, one line per log message. This doesn't need the heap,
+/// so it also works when the heap is exhausted or broken.
+pub fn log_interrupt_statistics() {
+    let mut logger = LineLogger::new();
+    // Writing to the logger never fails
+    let _ = write!(logger, "{}", interrupt_statistics());
+    logger.flush();
+}
+
+/// Collects formatted text into lines and logs each of them. Lines longer than the buffer are
+/// truncated.
+struct LineLogger {
+    buffer: [u8; 80],
+    len: usize,
+}
+
+impl LineLogger {
+    fn new() -> Self {
+        LineLogger {
+            buffer: [0; 80],
+            len: 0,
+        }
+    }
+
+    /// Logs the collected line, if any.
+    fn flush(&mut self) {
+        if self.len > 0 {
+            // Truncation may have split a character, so only the valid prefix is logged
+            let line = match core::str::from_utf8(&self.buffer[..self.len]) {
+                Ok(line) => line,
+                Err(error) => {
+                    core::str::from_utf8(&self.buffer[..error.valid_up_to()]).unwrap_or_default()
+                }
+            };
+            info!("{}", line);
+            self.len = 0;
+        }
+    }
+}
+
+impl Write for LineLogger {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        for &byte in text.as_bytes() {
+            if byte == b'\n' {
+                self.flush();
+            } else if self.len < self.buffer.len() {
+                self.buffer[self.len] = byte;
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+
+    use super::*;
+    use crate::interrupt::handlers::Exception;
+
+    #[test_case]
+    fn test_exceptions_and_irqs_are_counted() {
+        let before = interrupt_statistics();
+        x86_64::instructions::interrupts::int3();
+        // Wait for at least one timer interrupt
+        let timer_vector = PIC_1_OFFSET;
+        while interrupt_count(timer_vector) == before.count(timer_vector) {
+            x86_64::instructions::hlt();
+        }
+
+        let delta = interrupt_statistics().since(&before);
+        assert_eq!(delta.count(Exception::Breakpoint.vector()), 1);
+        assert!(delta.count(timer_vector) >= 1);
+        let table = format!("{}", delta);
+        assert!(table.contains("exception #BP"));
+        assert!(table.contains("IRQ 0 (timer)"));
+    }
+}
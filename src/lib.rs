@@ -16,6 +16,8 @@ use bootloader::entry_point;
 use bootloader::BootInfo;
 use log::info;
 
+pub mod acpi;
+pub mod cpu;
 pub mod gdt;
 pub mod interrupt;
 #[doc(hidden)]
@@ -23,6 +25,7 @@ pub mod io;
 pub mod keyboard;
 pub mod logging;
 pub mod memory;
+pub mod pci;
 pub mod qemu;
 pub mod sync;
 pub mod task;
@@ -50,14 +53,21 @@ pub fn initialize(boot_info: &'static BootInfo) {
     logging::initialize_logging();
 
     info!("Initializing OS...");
+    // The double fault handler needs a stack before the guarded ones can be allocated
+    info!("  - bootstrap global descriptor table");
+    gdt::initialize_bootstrap_global_descriptor_table();
     info!("  - interrupt descriptor table");
     interrupt::initialize_interrupt_descriptor_table();
+    info!("  - heap allocator");
+    memory::initialize_heap_allocator(boot_info);
+    info!("  - W^X page protection");
+    memory::enforce_write_xor_execute();
+    info!("  - CPU security features");
+    cpu::enable_security_features();
     info!("  - global descriptor table");
     gdt::initialize_global_descriptor_table();
     info!("  - interrupt controller");
     interrupt::initialize_interrupt_controller();
-    info!("  - heap allocator");
-    memory::initialize_heap_allocator(boot_info);
     info!("  - PS/2 controller");
     keyboard::initialize_ps2_controller().unwrap();
     info!("Initialization complete.");
@@ -69,6 +79,11 @@ pub fn initialize(boot_info: &'static BootInfo) {
             device_info.full_class,
             tinypci::name_for_vendor_id(device_info.vendor_id)
         );
+        for function in pci::PciFunction::all(&device_info) {
+            for (index, bar) in pci::bars(function) {
+                info!("  function {} BAR{}: {:x?}", function.function, index, bar);
+            }
+        }
     }
 }
 
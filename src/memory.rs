@@ -1,47 +1,186 @@
 use alloc::alloc::Layout;
 
 use bootloader::BootInfo;
-use x86_64::VirtAddr;
+use conquer_once::spin::OnceCell;
+use x86_64::{
+    structures::paging::{OffsetPageTable, PageTableFlags},
+    VirtAddr,
+};
 
 use crate::sync::Mutex;
 
 pub use self::{
+    address_space::{activate_kernel_address_space, AddressSpace, UserMapError},
     bump_allocator::BumpAllocator,
-    fixed_size_block_allocator::FixedSizeBlockAllocator,
+    dma::{allocate_dma, DmaBuffer, DmaConstraints, DmaError},
+    fixed_size_block_allocator::{BlockSizeStatistics, FixedSizeBlockAllocator, HeapStatistics},
     frame_allocator::BootInfoFrameAllocator,
-    heap::{HEAP_SIZE, HEAP_START},
-    linked_list_allocator::LinkedListAllocator,
+    heap::{set_heap_size_limit, HEAP_MAX_SIZE, HEAP_SIZE},
+    layout::{
+        area, area_containing, areas, Area, AreaKind, HEAP_AREA_SIZE, HEAP_START,
+        KERNEL_STACKS_SIZE, KERNEL_STACKS_START, MMIO_SIZE, MMIO_START, USER_SIZE, USER_START,
+        VMALLOC_SIZE, VMALLOC_START,
+    },
+    linked_list_allocator::{FitPolicy, LinkedListAllocator},
+    mappings::{log_mappings, mappings, page_mapping, Mapping, Mappings},
+    mmio::{map_mmio, map_mmio_with_cache_mode, CacheMode, MmioRegion},
+    protection::enforce_write_xor_execute,
+    slab::SlabCache,
+    stack::{KernelStack, KERNEL_STACK_SIZE},
+    user_copy::{copy_from_user, copy_to_user, UserCopyError},
+    vmalloc::{vmalloc, vmalloc_lazy, VirtualRegion, VmallocError},
+};
+
+pub(crate) use self::{
+    address_space::{handle_copy_on_write_fault, COPY_ON_WRITE},
+    demand_paging::handle_page_fault,
 };
 
+mod address_space;
 mod bump_allocator;
+mod demand_paging;
+mod dma;
 mod fixed_size_block_allocator;
 mod frame_allocator;
 mod heap;
+mod layout;
 mod linked_list_allocator;
+mod mappings;
+mod mmio;
+mod protection;
+mod slab;
+mod stack;
+mod user_copy;
+mod vmalloc;
+
+const PAGE_SIZE: usize = 4096;
+/// The size of the huge pages used for large mappings.
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+/// The size of the largest pages, which are used for very large mappings if the CPU has them.
+const GIGANTIC_PAGE_SIZE: usize = 1024 * 1024 * 1024;
+
+/// Flags for mappings of kernel data like the heap and stacks, which must never be executable.
+const DATA_FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PageTableFlags::PRESENT.bits()
+        | PageTableFlags::WRITABLE.bits()
+        | PageTableFlags::NO_EXECUTE.bits(),
+);
+
+#[cfg(any(
+    all(feature = "alloc-bump", feature = "alloc-fixed-block"),
+    all(feature = "alloc-bump", feature = "alloc-linked-list"),
+    all(feature = "alloc-fixed-block", feature = "alloc-linked-list"),
+    not(any(
+        feature = "alloc-bump",
+        feature = "alloc-fixed-block",
+        feature = "alloc-linked-list"
+    )),
+))]
+compile_error!(
+    "exactly one of the `alloc-bump`, `alloc-fixed-block` and `alloc-linked-list` features must be \
+     enabled"
+);
+
+#[cfg(feature = "alloc-bump")]
+type HeapAllocator = BumpAllocator;
+#[cfg(feature = "alloc-fixed-block")]
+type HeapAllocator = FixedSizeBlockAllocator;
+#[cfg(feature = "alloc-linked-list")]
+type HeapAllocator = LinkedListAllocator;
 
 #[global_allocator]
-static HEAP_ALLOCATOR: Mutex<FixedSizeBlockAllocator> = Mutex::new(FixedSizeBlockAllocator::new());
+static HEAP_ALLOCATOR: Mutex<HeapAllocator> = Mutex::new(HeapAllocator::new());
+
+// When both are needed, the mapper must be locked before the frame allocator. The heap needs both
+// to map more memory, so it can't grow while either lock is held and allocations may fail then.
+static MAPPER: OnceCell<Mutex<OffsetPageTable<'static>>> = OnceCell::uninit();
+static FRAME_ALLOCATOR: OnceCell<Mutex<BootInfoFrameAllocator>> = OnceCell::uninit();
+static PHYSICAL_MEMORY_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: Layout) -> ! {
     panic!("allocation error: {:?}", layout);
 }
 
+/// Set up the page table mapper and frame allocator, then map and initialize the kernel heap.
+///
+/// This must be called only once.
 pub fn initialize_heap_allocator(boot_info: &'static BootInfo) {
+    // All data mappings are made non-executable
+    protection::enable_no_execute();
+    mmio::initialize_page_attribute_table();
+
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { frame_allocator::initialize_mapper(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::new(&boot_info.memory_map) };
-    heap::initialize(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    PHYSICAL_MEMORY_OFFSET.init_once(|| phys_mem_offset);
+    let phys_mem_size = boot_info
+        .memory_map
+        .iter()
+        .map(|region| region.range.end_addr())
+        .max()
+        .unwrap_or(0);
+    layout::initialize(
+        phys_mem_offset,
+        align_up(phys_mem_size as usize, PAGE_SIZE) as u64,
+    );
+    MAPPER.init_once(|| Mutex::new(unsafe { frame_allocator::initialize_mapper(phys_mem_offset) }));
+    FRAME_ALLOCATOR.init_once(|| {
+        Mutex::new(unsafe { BootInfoFrameAllocator::new(&boot_info.memory_map, phys_mem_offset) })
+    });
+
+    address_space::initialize(&mut mapper().lock(), &mut frame_allocator().lock());
+    heap::initialize(&mut *mapper().lock(), &mut *frame_allocator().lock())
+        .expect("heap initialization failed");
 
     unsafe {
         HEAP_ALLOCATOR.lock().initialize(HEAP_START, HEAP_SIZE);
     }
 }
 
+/// Returns a snapshot of the global heap allocator's usage counters.
+#[cfg(feature = "alloc-fixed-block")]
+pub fn heap_statistics() -> HeapStatistics {
+    HEAP_ALLOCATOR.lock().statistics()
+}
+
+/// Returns the blocks cached by the global heap allocator to its fallback heap, so that large
+/// allocations can use that memory. Returns the number of bytes reclaimed.
+#[cfg(feature = "alloc-fixed-block")]
+pub fn reclaim_heap() -> usize {
+    HEAP_ALLOCATOR.lock().reclaim_free_blocks()
+}
+
+/// The mapper for the active level 4 page table.
+///
+/// # Panics
+/// Panics if the memory subsystem hasn't been initialized yet.
+pub fn mapper() -> &'static Mutex<OffsetPageTable<'static>> {
+    MAPPER.get().expect("mapper is uninitialized")
+}
+
+/// The global physical frame allocator.
+///
+/// # Panics
+/// Panics if the memory subsystem hasn't been initialized yet.
+pub fn frame_allocator() -> &'static Mutex<BootInfoFrameAllocator> {
+    FRAME_ALLOCATOR
+        .get()
+        .expect("frame allocator is uninitialized")
+}
+
+/// The virtual address at which the bootloader mapped the complete physical memory.
+///
+/// # Panics
+/// Panics if the memory subsystem hasn't been initialized yet.
+pub fn physical_memory_offset() -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET
+        .get()
+        .expect("physical memory offset is uninitialized")
+}
+
 /// Align the given address `addr` upwards to nearest `alignment`.
 ///
 /// Requires that `alignment` is a power of two.
-fn align_up(addr: usize, alignment: usize) -> usize {
+const fn align_up(addr: usize, alignment: usize) -> usize {
     assert!(alignment.count_ones() == 1);
     // Round addr + alignment - 1 down to the nearest multiple of alignment
     (addr + alignment - 1) & !(alignment - 1)
@@ -0,0 +1,547 @@
+use core::ptr;
+
+use conquer_once::spin::OnceCell;
+use x86_64::{
+    instructions::tlb,
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, TranslateResult, UnmapError},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PageTableIndex, PhysFrame, Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::memory::{
+    layout::{self, AreaKind, USER_SIZE, USER_START},
+    BootInfoFrameAllocator, PAGE_SIZE,
+};
+
+/// The number of bytes mapped by a single level 4 entry.
+const LEVEL_4_ENTRY_SIZE: usize = 512 * 1024 * 1024 * 1024;
+
+const _: () = assert!(
+    USER_START.is_multiple_of(LEVEL_4_ENTRY_SIZE) && USER_SIZE.is_multiple_of(LEVEL_4_ENTRY_SIZE)
+);
+
+/// Flags for the page tables of user mappings. The leaf entries decide the actual permissions.
+const USER_TABLE_FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PageTableFlags::PRESENT.bits()
+        | PageTableFlags::WRITABLE.bits()
+        | PageTableFlags::USER_ACCESSIBLE.bits(),
+);
+
+/// Marks read-only user pages whose frame is shared with other address spaces, and which get a
+/// private copy of the frame on the first write.
+pub(crate) const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// The level 4 table set up by the bootloader, which holds the kernel's mappings.
+static KERNEL_LEVEL_4_FRAME: OnceCell<PhysFrame> = OnceCell::uninit();
+
+/// Why a user page couldn't be mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserMapError {
+    /// The page lies outside of the user area.
+    NotInUserSpace,
+    AlreadyMapped,
+    OutOfFrames,
+}
+
+impl From<MapToError<Size4KiB>> for UserMapError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        match error {
+            MapToError::FrameAllocationFailed => Self::OutOfFrames,
+            _ => Self::AlreadyMapped,
+        }
+    }
+}
+
+/// A set of page tables that shares the kernel's mappings and has its own user area.
+///
+/// Only the level 4 table is copied from the kernel, so the kernel's lower level tables are
+/// shared and later kernel mappings show up in every address space. User pages are backed by
+/// frames owned by the address space, or shared with its forks until written, which are
+/// released along with its page tables when it is dropped.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an address space with the kernel's mappings and an empty user area.
+    pub fn new() -> Result<Self, UserMapError> {
+        let level_4_frame = crate::memory::frame_allocator()
+            .lock()
+            .allocate_frame()
+            .ok_or(UserMapError::OutOfFrames)?;
+        let level_4_table = unsafe { &mut *table_ptr(level_4_frame) };
+        let kernel_table = unsafe { &*table_ptr(kernel_level_4_frame()) };
+        for (index, entry) in level_4_table.iter_mut().enumerate() {
+            if is_user_index(index) {
+                entry.set_unused();
+            } else {
+                *entry = kernel_table[index].clone();
+            }
+        }
+        Ok(Self { level_4_frame })
+    }
+
+    /// The frame holding the level 4 table, which is loaded into CR3 to activate the address
+    /// space.
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Returns whether this is the active address space.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Switches to this address space.
+    ///
+    /// # Safety
+    /// The address space must stay alive while it is active, so switch to another one, e.g. with
+    /// [`activate_kernel_address_space`], before dropping it.
+    pub unsafe fn activate(&self) {
+        let (_, flags) = Cr3::read();
+        unsafe { Cr3::write(self.level_4_frame, flags) };
+    }
+
+    /// Maps a zeroed frame at the given page of the user area, with the given flags in addition
+    /// to `PRESENT` and `USER_ACCESSIBLE`, and returns the frame.
+    pub fn map_user_page(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Result<PhysFrame, UserMapError> {
+        if !is_user_address(page.start_address()) {
+            return Err(UserMapError::NotInUserSpace);
+        }
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+        let mut frame_allocator = crate::memory::frame_allocator().lock();
+        let frame: PhysFrame = frame_allocator
+            .allocate_frame()
+            .ok_or(UserMapError::OutOfFrames)?;
+        unsafe { frame_ptr(frame).write_bytes(0, PAGE_SIZE) };
+
+        let result = unsafe {
+            self.mapper().map_to_with_table_flags(
+                page,
+                frame,
+                flags,
+                USER_TABLE_FLAGS,
+                &mut *frame_allocator,
+            )
+        };
+        match result {
+            // The flush only matters if this address space is active
+            Ok(flush) => flush.flush(),
+            Err(error) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                return Err(error.into());
+            }
+        }
+        Ok(frame)
+    }
+
+    /// Creates a copy of this address space whose user pages share their frames with this one.
+    ///
+    /// Writable pages become read-only copy-on-write pages in both address spaces, and the first
+    /// write to such a page gives the writing address space its own copy of the frame.
+    pub fn fork(&mut self) -> Result<AddressSpace, UserMapError> {
+        let mut child = AddressSpace::new()?;
+        let mut frame_allocator = crate::memory::frame_allocator().lock();
+        let mut child_mapper = child.mapper();
+        let level_4_table = unsafe { &mut *table_ptr(self.level_4_frame) };
+        let mut result = Ok(());
+        for (index, entry) in level_4_table.iter().enumerate() {
+            if is_user_index(index) && !entry.is_unused() {
+                let start = VirtAddr::new((index * LEVEL_4_ENTRY_SIZE) as u64);
+                let level_3_frame = PhysFrame::containing_address(entry.addr());
+                result = unsafe {
+                    share_table(
+                        &mut child_mapper,
+                        &mut frame_allocator,
+                        level_3_frame,
+                        3,
+                        start,
+                    )
+                };
+                if result.is_err() {
+                    break;
+                }
+            }
+        }
+        // Dropping a partial child on failure needs the frame allocator
+        drop(frame_allocator);
+
+        // Writes through stale TLB entries would bypass the copy
+        if self.is_active() {
+            tlb::flush_all();
+        }
+        result.map(|()| child)
+    }
+
+    /// Unmaps a user page and frees its frame. Does nothing if the page isn't mapped.
+    pub fn unmap_user_page(&mut self, page: Page) {
+        if !is_user_address(page.start_address()) {
+            return;
+        }
+        match self.mapper().unmap(page) {
+            Ok((frame, flush)) => {
+                flush.flush();
+                unsafe {
+                    crate::memory::frame_allocator()
+                        .lock()
+                        .deallocate_frame(frame)
+                };
+            }
+            Err(UnmapError::PageNotMapped) => {}
+            Err(error) => panic!("failed to unmap user page {:?}: {:?}", page, error),
+        }
+    }
+
+    /// Translates an address of this address space to the physical address it maps to.
+    pub fn translate_addr(&mut self, address: VirtAddr) -> Option<PhysAddr> {
+        self.mapper().translate_addr(address)
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe {
+            OffsetPageTable::new(
+                &mut *table_ptr(self.level_4_frame),
+                crate::memory::physical_memory_offset(),
+            )
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropped the active address space");
+        let mut frame_allocator = crate::memory::frame_allocator().lock();
+        let level_4_table = unsafe { &mut *table_ptr(self.level_4_frame) };
+        for (index, entry) in level_4_table.iter_mut().enumerate() {
+            if is_user_index(index) && !entry.is_unused() {
+                let level_3_frame = PhysFrame::containing_address(entry.addr());
+                unsafe { free_table(&mut frame_allocator, level_3_frame, 3) };
+            }
+        }
+        unsafe { frame_allocator.deallocate_frame(self.level_4_frame) };
+    }
+}
+
+/// Switches back to the kernel's own address space.
+pub fn activate_kernel_address_space() {
+    let (_, flags) = Cr3::read();
+    unsafe { Cr3::write(kernel_level_4_frame(), flags) };
+}
+
+/// Records the kernel's level 4 table and gives each kernel area its level 3 tables up front.
+/// Address spaces copy the level 4 entries once, so kernel mappings made later must only ever
+/// touch the shared lower level tables.
+pub(crate) fn initialize(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) {
+    KERNEL_LEVEL_4_FRAME.init_once(|| Cr3::read().0);
+
+    let physical_memory_offset = mapper.phys_offset();
+    let level_4_table = mapper.level_4_table();
+    for area in layout::areas().filter(|area| area.kind != AreaKind::User) {
+        let first = area.start.p4_index();
+        let last = (area.end() - 1u64).p4_index();
+        for index in u16::from(first)..=u16::from(last) {
+            let entry = &mut level_4_table[PageTableIndex::new(index)];
+            if entry.is_unused() {
+                let frame: PhysFrame = frame_allocator
+                    .allocate_frame()
+                    .expect("no frames left for kernel page tables");
+                let table: *mut PageTable =
+                    (physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr();
+                unsafe { table.write(PageTable::new()) };
+                entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+            }
+        }
+    }
+}
+
+fn kernel_level_4_frame() -> PhysFrame {
+    *KERNEL_LEVEL_4_FRAME
+        .get()
+        .expect("kernel address space is uninitialized")
+}
+
+fn is_user_index(index: usize) -> bool {
+    let address = index * LEVEL_4_ENTRY_SIZE;
+    (USER_START..USER_START + USER_SIZE).contains(&address)
+}
+
+fn is_user_address(address: VirtAddr) -> bool {
+    layout::area(AreaKind::User).contains(address)
+}
+
+fn frame_ptr(frame: PhysFrame) -> *mut u8 {
+    (crate::memory::physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    frame_ptr(frame).cast()
+}
+
+/// Called by the page fault handler for write faults on present pages. If the page is a
+/// copy-on-write page of the active address space, it gets a private copy of its frame, or just
+/// becomes writable if no other address space shares the frame anymore.
+///
+/// Returns whether the fault was handled.
+pub(crate) fn handle_copy_on_write_fault(address: VirtAddr) -> bool {
+    let (level_4_frame, _) = Cr3::read();
+    // The kernel's own tables are managed through the global mapper instead
+    if !is_user_address(address) || KERNEL_LEVEL_4_FRAME.get() == Some(&level_4_frame) {
+        return false;
+    }
+    let mut frame_allocator = match crate::memory::frame_allocator().try_lock() {
+        Some(frame_allocator) => frame_allocator,
+        None => return false,
+    };
+
+    let mut mapper = unsafe {
+        OffsetPageTable::new(
+            &mut *table_ptr(level_4_frame),
+            crate::memory::physical_memory_offset(),
+        )
+    };
+    let (frame, flags) = match mapper.translate(address) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } if flags.contains(COPY_ON_WRITE) => (frame, flags),
+        _ => return false,
+    };
+    let page: Page = Page::containing_address(address);
+    let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+
+    if frame_allocator.reference_count(frame) == 1 {
+        match unsafe { mapper.update_flags(page, flags) } {
+            Ok(flush) => flush.flush(),
+            Err(_) => return false,
+        }
+        return true;
+    }
+
+    let copy: PhysFrame = match frame_allocator.allocate_frame() {
+        Some(copy) => copy,
+        None => return false,
+    };
+    unsafe { ptr::copy_nonoverlapping(frame_ptr(frame), frame_ptr(copy), PAGE_SIZE) };
+    // The new mapping is flushed below
+    match mapper.unmap(page) {
+        Ok((_, flush)) => flush.ignore(),
+        Err(_) => return false,
+    }
+    match unsafe { mapper.map_to(page, copy, flags, &mut *frame_allocator) } {
+        Ok(flush) => flush.flush(),
+        Err(error) => panic!("failed to remap copy-on-write page {:?}: {:?}", page, error),
+    }
+    unsafe { frame_allocator.deallocate_frame(frame) };
+    true
+}
+
+/// Maps every page below the page table in the given frame into `child_mapper` as well, with the
+/// frames shared between both. `start` is the first address covered by the table.
+///
+/// # Safety
+/// The table must be a user page table of an address space that isn't being modified elsewhere.
+unsafe fn share_table(
+    child_mapper: &mut OffsetPageTable<'_>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    frame: PhysFrame,
+    level: u8,
+    start: VirtAddr,
+) -> Result<(), UserMapError> {
+    let entry_size = (PAGE_SIZE as u64) << (9 * (level - 1));
+    let table = unsafe { &mut *table_ptr(frame) };
+    for (index, entry) in table.iter_mut().enumerate() {
+        if entry.is_unused() {
+            continue;
+        }
+        let address = start + index as u64 * entry_size;
+        let entry_frame = PhysFrame::containing_address(entry.addr());
+        if level > 1 {
+            assert!(
+                !entry.flags().contains(PageTableFlags::HUGE_PAGE),
+                "can't share huge user page at {:?}",
+                address
+            );
+            unsafe {
+                share_table(
+                    child_mapper,
+                    frame_allocator,
+                    entry_frame,
+                    level - 1,
+                    address,
+                )?
+            };
+            continue;
+        }
+
+        let mut flags = entry.flags();
+        if flags.contains(PageTableFlags::WRITABLE) {
+            flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+            entry.set_flags(flags);
+        }
+        let page: Page = Page::containing_address(address);
+        unsafe {
+            child_mapper
+                .map_to_with_table_flags(
+                    page,
+                    entry_frame,
+                    flags,
+                    USER_TABLE_FLAGS,
+                    frame_allocator,
+                )?
+                .ignore()
+        };
+        frame_allocator.share_frame(entry_frame);
+    }
+    Ok(())
+}
+
+/// Frees the page table in the given frame and every table below it, and drops a reference to
+/// every frame they map.
+///
+/// # Safety
+/// Nothing may use the table or the mapped frames anymore.
+unsafe fn free_table(frame_allocator: &mut BootInfoFrameAllocator, frame: PhysFrame, level: u8) {
+    let table = unsafe { &*table_ptr(frame) };
+    for entry in table.iter().filter(|entry| !entry.is_unused()) {
+        let entry_frame = PhysFrame::containing_address(entry.addr());
+        if level == 1 {
+            unsafe { frame_allocator.deallocate_frame(entry_frame) };
+        } else if level == 2 && entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            let huge_frame = PhysFrame::<Size2MiB>::containing_address(entry.addr());
+            unsafe { frame_allocator.deallocate_frame(huge_frame) };
+        } else {
+            assert!(
+                !entry.flags().contains(PageTableFlags::HUGE_PAGE),
+                "unexpected 1 GiB user page"
+            );
+            unsafe { free_table(frame_allocator, entry_frame, level - 1) };
+        }
+    }
+    unsafe { FrameDeallocator::<Size4KiB>::deallocate_frame(frame_allocator, frame) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{copy_from_user, copy_to_user};
+
+    #[test_case]
+    fn test_address_space_isolates_user_pages() {
+        let free_frames = crate::memory::frame_allocator().lock().free_frames();
+        let page = Page::containing_address(VirtAddr::new(USER_START as u64 + 0x40_0000));
+
+        let mut address_space = AddressSpace::new().unwrap();
+        let frame = address_space
+            .map_user_page(page, PageTableFlags::WRITABLE)
+            .unwrap();
+        unsafe { frame_ptr(frame).write(42) };
+        assert_eq!(
+            address_space.map_user_page(page, PageTableFlags::WRITABLE),
+            Err(UserMapError::AlreadyMapped)
+        );
+        assert_eq!(
+            address_space.map_user_page(
+                Page::containing_address(VirtAddr::new(0x1000)),
+                PageTableFlags::WRITABLE
+            ),
+            Err(UserMapError::NotInUserSpace)
+        );
+
+        // The page is only visible while the address space is active
+        let mut value = [0u8];
+        let user_ptr = page.start_address().as_ptr::<u8>();
+        unsafe {
+            address_space.activate();
+            let result = copy_from_user(&mut value, user_ptr);
+            activate_kernel_address_space();
+            result.unwrap();
+        }
+        assert_eq!(value[0], 42);
+        assert!(crate::memory::page_mapping(page.start_address()).is_none());
+
+        drop(address_space);
+        assert_eq!(
+            crate::memory::frame_allocator().lock().free_frames(),
+            free_frames
+        );
+    }
+
+    #[test_case]
+    fn test_forked_address_spaces_diverge_on_write() {
+        let free_frames = crate::memory::frame_allocator().lock().free_frames();
+        let page = Page::containing_address(VirtAddr::new(USER_START as u64 + 0x40_0000));
+        let user_ptr = page.start_address().as_mut_ptr::<u8>();
+
+        let mut parent = AddressSpace::new().unwrap();
+        let frame = parent
+            .map_user_page(page, PageTableFlags::WRITABLE)
+            .unwrap();
+        unsafe { frame_ptr(frame).write(1) };
+        let mut child = parent.fork().unwrap();
+        assert_eq!(
+            child.translate_addr(page.start_address()),
+            Some(frame.start_address())
+        );
+        assert_eq!(
+            crate::memory::frame_allocator()
+                .lock()
+                .reference_count(frame),
+            2
+        );
+
+        // The child's write faults and copies the frame
+        let mut value = [0u8];
+        unsafe {
+            child.activate();
+            copy_to_user(user_ptr, &[2]).unwrap();
+            copy_from_user(&mut value, user_ptr).unwrap();
+            activate_kernel_address_space();
+        }
+        assert_eq!(value[0], 2);
+        assert_ne!(
+            child.translate_addr(page.start_address()),
+            Some(frame.start_address())
+        );
+        assert_eq!(
+            crate::memory::frame_allocator()
+                .lock()
+                .reference_count(frame),
+            1
+        );
+
+        // The parent is the last owner, so its write just makes the page writable again
+        unsafe {
+            parent.activate();
+            copy_from_user(&mut value, user_ptr).unwrap();
+            assert_eq!(value[0], 1);
+            copy_to_user(user_ptr, &[3]).unwrap();
+            activate_kernel_address_space();
+        }
+        assert_eq!(
+            parent.translate_addr(page.start_address()),
+            Some(frame.start_address())
+        );
+        assert_eq!(unsafe { frame_ptr(frame).read() }, 3);
+
+        drop(child);
+        drop(parent);
+        assert_eq!(
+            crate::memory::frame_allocator().lock().free_frames(),
+            free_frames
+        );
+    }
+}
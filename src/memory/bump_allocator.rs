@@ -1,7 +1,10 @@
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
-use crate::{memory::align_up, sync::Mutex};
+use crate::{
+    memory::{align_up, heap},
+    sync::Mutex,
+};
 
 pub struct BumpAllocator {
     heap_start: usize,
@@ -43,6 +46,12 @@ unsafe impl GlobalAlloc for Mutex<BumpAllocator> {
             None => return ptr::null_mut(),
         };
 
+        if alloc_end > allocator.heap_end {
+            // Try to map more memory before giving up
+            let heap_end = allocator.heap_end;
+            allocator.heap_end += heap::grow(heap_end, alloc_end - heap_end);
+        }
+
         if alloc_end > allocator.heap_end {
             ptr::null_mut() // out of memory
         } else {
@@ -0,0 +1,89 @@
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, Mapper, Page, PhysFrame},
+    VirtAddr,
+};
+
+use crate::{
+    memory::{DATA_FLAGS, PAGE_SIZE},
+    sync::Mutex,
+};
+
+/// The maximum number of lazily-backed regions that can be registered at once.
+const MAX_LAZY_REGIONS: usize = 32;
+
+/// Start and end addresses of the regions whose pages are mapped on first access. This is a
+/// fixed-size table so the page fault handler never needs the heap.
+static LAZY_REGIONS: Mutex<[Option<(VirtAddr, VirtAddr)>; MAX_LAZY_REGIONS]> =
+    Mutex::new([None; MAX_LAZY_REGIONS]);
+
+/// Registers the range from `start` to `end` (exclusive) as lazily backed.
+///
+/// Returns `false` if the table of lazy regions is full.
+pub(crate) fn register_lazy_region(start: VirtAddr, end: VirtAddr) -> bool {
+    let mut regions = LAZY_REGIONS.lock();
+    match regions.iter_mut().find(|region| region.is_none()) {
+        Some(slot) => {
+            *slot = Some((start, end));
+            true
+        }
+        None => false,
+    }
+}
+
+/// Removes the lazily-backed region starting at `start`. Pages that were already mapped stay
+/// mapped.
+pub(crate) fn unregister_lazy_region(start: VirtAddr) {
+    let mut regions = LAZY_REGIONS.lock();
+    if let Some(slot) = regions
+        .iter_mut()
+        .find(|region| matches!(region, Some((region_start, _)) if *region_start == start))
+    {
+        *slot = None;
+    }
+}
+
+/// Called by the page fault handler for faults on non-present pages. If the address lies in a
+/// lazily-backed region, a zeroed frame is mapped at the faulting page.
+///
+/// Returns whether the fault was handled. Faults that can't be handled, including those raised
+/// while the memory subsystem is locked, are left to the caller to report.
+pub(crate) fn handle_page_fault(address: VirtAddr) -> bool {
+    let in_lazy_region = match LAZY_REGIONS.try_lock() {
+        Some(regions) => regions
+            .iter()
+            .flatten()
+            .any(|&(start, end)| start <= address && address < end),
+        None => false,
+    };
+    if !in_lazy_region {
+        return false;
+    }
+
+    let (mut mapper, mut frame_allocator) = match (
+        crate::memory::mapper().try_lock(),
+        crate::memory::frame_allocator().try_lock(),
+    ) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return false,
+    };
+
+    let frame: PhysFrame = match frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    let frame_ptr: *mut u8 =
+        (crate::memory::physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr();
+    unsafe { frame_ptr.write_bytes(0, PAGE_SIZE) };
+
+    let page = Page::containing_address(address);
+    match unsafe { mapper.map_to(page, frame, DATA_FLAGS, &mut *frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            false
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use core::slice;
+
+use x86_64::{structures::paging::PhysFrame, PhysAddr, VirtAddr};
+
+use crate::memory::PAGE_SIZE;
+
+/// Requirements a device places on the physical placement of a DMA buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaConstraints {
+    /// The alignment of the buffer's physical address in bytes. Must be a power of two, and is
+    /// at least a page.
+    pub alignment: usize,
+    /// The buffer must not cross a multiple of this many bytes, e.g. 64 KiB for ISA DMA. Must be
+    /// a power of two.
+    pub boundary: Option<usize>,
+    /// The whole buffer must lie below this physical address, e.g. 4 GiB for devices that only
+    /// use 32-bit addresses.
+    pub max_address: PhysAddr,
+}
+
+impl Default for DmaConstraints {
+    fn default() -> Self {
+        Self {
+            alignment: PAGE_SIZE,
+            boundary: None,
+            max_address: PhysAddr::new(u64::MAX >> 12),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    /// The constraints can't be met by any buffer of the requested size, e.g. because the
+    /// buffer is larger than the boundary.
+    InvalidConstraints,
+    /// No free physical range satisfies the constraints.
+    OutOfMemory,
+}
+
+/// A zeroed buffer of physically contiguous memory that devices can access directly.
+///
+/// The buffer is accessed through the physical memory mapping, so it is cacheable. That is fine
+/// on x86_64, where DMA is cache coherent. The frames are freed when the buffer is dropped, so a
+/// device must be done with the buffer by then.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    start: PhysFrame,
+    frames: usize,
+    len: usize,
+}
+
+impl DmaBuffer {
+    /// The physical address to hand to the device.
+    pub fn physical_address(&self) -> PhysAddr {
+        self.start.start_address()
+    }
+
+    /// The virtual address the kernel can use to access the buffer.
+    pub fn virtual_address(&self) -> VirtAddr {
+        crate::memory::physical_memory_offset() + self.start.start_address().as_u64()
+    }
+
+    /// The requested length of the buffer in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.virtual_address().as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.virtual_address().as_mut_ptr(), self.len) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            crate::memory::frame_allocator()
+                .lock()
+                .deallocate_contiguous(self.start, self.frames)
+        };
+    }
+}
+
+/// Allocates a physically contiguous buffer of `len` bytes that satisfies the constraints.
+pub fn allocate_dma(len: usize, constraints: DmaConstraints) -> Result<DmaBuffer, DmaError> {
+    let alignment = constraints.alignment.max(PAGE_SIZE);
+    let boundary = constraints.boundary.unwrap_or(0);
+    if !alignment.is_power_of_two() || (boundary != 0 && !boundary.is_power_of_two()) {
+        return Err(DmaError::InvalidConstraints);
+    }
+    let frames = len.max(1).div_ceil(PAGE_SIZE);
+    if boundary != 0 && frames * PAGE_SIZE > boundary {
+        return Err(DmaError::InvalidConstraints);
+    }
+
+    let limit = (constraints.max_address.as_u64() / PAGE_SIZE as u64) as usize;
+    let start = crate::memory::frame_allocator()
+        .lock()
+        .allocate_contiguous(
+            frames,
+            alignment / PAGE_SIZE,
+            boundary.div_ceil(PAGE_SIZE),
+            limit,
+        )
+        .ok_or(DmaError::OutOfMemory)?;
+
+    let mut buffer = DmaBuffer { start, frames, len };
+    buffer.as_mut_slice().fill(0);
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_dma_buffer_respects_constraints() {
+        let free_frames = crate::memory::frame_allocator().lock().free_frames();
+        let constraints = DmaConstraints {
+            alignment: 16 * 1024,
+            boundary: Some(64 * 1024),
+            max_address: PhysAddr::new(16 * 1024 * 1024),
+        };
+
+        let mut buffer = allocate_dma(24 * 1024, constraints).unwrap();
+        let start = buffer.physical_address().as_u64();
+        let end = start + buffer.len() as u64;
+        assert_eq!(start % (16 * 1024), 0);
+        assert_eq!(start / (64 * 1024), (end - 1) / (64 * 1024));
+        assert!(end <= 16 * 1024 * 1024);
+        assert!(buffer.as_slice().iter().all(|&byte| byte == 0));
+
+        buffer.as_mut_slice()[100] = 0xab;
+        let direct: *const u8 = (crate::memory::physical_memory_offset() + start + 100u64).as_ptr();
+        assert_eq!(unsafe { direct.read_volatile() }, 0xab);
+
+        drop(buffer);
+        assert_eq!(
+            crate::memory::frame_allocator().lock().free_frames(),
+            free_frames
+        );
+        assert_eq!(
+            allocate_dma(128 * 1024, constraints).unwrap_err(),
+            DmaError::InvalidConstraints
+        );
+    }
+}
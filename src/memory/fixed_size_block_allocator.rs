@@ -4,7 +4,10 @@ use core::{
     ptr::{self, NonNull},
 };
 
-use crate::sync::Mutex;
+use crate::{memory::heap, sync::Mutex};
+
+#[cfg(feature = "heap-debug")]
+mod debug;
 
 /// The block sizes to use.
 ///
@@ -26,9 +29,83 @@ struct Block {
     next: Option<&'static mut Block>,
 }
 
+/// Usage counters for a single block size.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BlockSizeStatistics {
+    pub block_size: usize,
+    /// The number of allocations served with this block size.
+    pub allocations: usize,
+    /// The number of blocks of this size that were freed.
+    pub frees: usize,
+    /// The number of blocks currently waiting in the free list.
+    pub free_list_length: usize,
+}
+
+/// A snapshot of the state of a [`FixedSizeBlockAllocator`].
+#[derive(Debug, Clone)]
+pub struct HeapStatistics {
+    /// The number of bytes currently allocated, as requested by the allocation layouts. With the
+    /// `heap-debug` feature, this includes the redzones around each allocation.
+    pub bytes_in_use: usize,
+    /// The highest value `bytes_in_use` has reached.
+    pub peak_bytes_in_use: usize,
+    /// Counters for each block size, from smallest to largest.
+    pub block_sizes: [BlockSizeStatistics; BLOCK_SIZES.len()],
+    /// The number of allocations too large for any block size.
+    pub fallback_allocations: usize,
+    /// The number of frees of allocations too large for any block size.
+    pub fallback_frees: usize,
+    /// The number of unallocated bytes in the fallback heap. Blocks sitting in the free lists
+    /// count as allocated here.
+    pub fallback_free_bytes: usize,
+}
+
+impl HeapStatistics {
+    const fn new() -> Self {
+        const EMPTY: BlockSizeStatistics = BlockSizeStatistics {
+            block_size: 0,
+            allocations: 0,
+            frees: 0,
+            free_list_length: 0,
+        };
+        let mut block_sizes = [EMPTY; BLOCK_SIZES.len()];
+        let mut index = 0;
+        while index < BLOCK_SIZES.len() {
+            block_sizes[index].block_size = BLOCK_SIZES[index];
+            index += 1;
+        }
+        Self {
+            bytes_in_use: 0,
+            peak_bytes_in_use: 0,
+            block_sizes,
+            fallback_allocations: 0,
+            fallback_frees: 0,
+            fallback_free_bytes: 0,
+        }
+    }
+
+    fn record_alloc(&mut self, layout: &Layout) {
+        self.bytes_in_use += layout.size();
+        self.peak_bytes_in_use = self.peak_bytes_in_use.max(self.bytes_in_use);
+        match block_size_index(layout) {
+            Some(index) => self.block_sizes[index].allocations += 1,
+            None => self.fallback_allocations += 1,
+        }
+    }
+
+    fn record_dealloc(&mut self, layout: &Layout) {
+        self.bytes_in_use -= layout.size();
+        match block_size_index(layout) {
+            Some(index) => self.block_sizes[index].frees += 1,
+            None => self.fallback_frees += 1,
+        }
+    }
+}
+
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut Block>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    statistics: HeapStatistics,
 }
 
 impl FixedSizeBlockAllocator {
@@ -40,6 +117,15 @@ impl FixedSizeBlockAllocator {
         Self {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            statistics: HeapStatistics::new(),
+        }
+    }
+
+    /// Returns a snapshot of the allocator's usage counters.
+    pub fn statistics(&self) -> HeapStatistics {
+        HeapStatistics {
+            fallback_free_bytes: self.fallback_allocator.free(),
+            ..self.statistics.clone()
         }
     }
 
@@ -55,8 +141,53 @@ impl FixedSizeBlockAllocator {
         }
     }
 
-    /// Allocates using the fallback allocator.
+    /// Returns every cached block in the free lists to the fallback allocator, so that the
+    /// memory can be used for allocations of any size again.
+    ///
+    /// Returns the number of bytes reclaimed.
+    pub fn reclaim_free_blocks(&mut self) -> usize {
+        let mut reclaimed = 0;
+        for (index, &block_size) in BLOCK_SIZES.iter().enumerate() {
+            // Blocks are always carved out of the fallback allocator with this layout
+            let layout = Layout::from_size_align(block_size, block_size).unwrap();
+            while let Some(block) = self.list_heads[index].take() {
+                self.list_heads[index] = block.next.take();
+                let ptr = NonNull::from(block).cast::<u8>();
+                unsafe {
+                    self.fallback_allocator.deallocate(ptr, layout);
+                }
+                reclaimed += block_size;
+            }
+            self.statistics.block_sizes[index].free_list_length = 0;
+        }
+        reclaimed
+    }
+
+    /// Allocates using the fallback allocator. If it is out of memory, cached blocks are
+    /// reclaimed first, and then the heap is grown.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+
+        if self.reclaim_free_blocks() > 0 {
+            if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+        }
+
+        // Request enough extra space to satisfy the alignment wherever the new memory starts
+        let added = heap::grow(
+            self.fallback_allocator.top(),
+            layout.size() + layout.align(),
+        );
+        if added == 0 {
+            return ptr::null_mut();
+        }
+        unsafe {
+            self.fallback_allocator.extend(added);
+        }
+
         match self.fallback_allocator.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
             Err(_) => ptr::null_mut(),
@@ -66,14 +197,19 @@ impl FixedSizeBlockAllocator {
 
 unsafe impl GlobalAlloc for Mutex<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // In debug mode, the allocated block also holds redzones around the caller's memory
+        #[cfg(feature = "heap-debug")]
+        let (requested_layout, layout) = (layout, debug::guarded_layout(layout));
+
         let mut allocator = self.lock();
-        match block_size_index(&layout) {
+        let ptr = match block_size_index(&layout) {
             Some(index) => {
                 // Grab the head of the block list for the given size
                 match allocator.list_heads[index].take() {
                     Some(node) => {
                         // Pop first block off the front of the list
                         allocator.list_heads[index] = node.next.take();
+                        allocator.statistics.block_sizes[index].free_list_length -= 1;
                         node as *mut Block as *mut u8
                     }
                     None => {
@@ -87,11 +223,25 @@ unsafe impl GlobalAlloc for Mutex<FixedSizeBlockAllocator> {
                 }
             }
             None => allocator.fallback_alloc(layout),
+        };
+
+        if !ptr.is_null() {
+            allocator.statistics.record_alloc(&layout);
         }
+
+        #[cfg(feature = "heap-debug")]
+        let ptr = unsafe { debug::guard(ptr, requested_layout) };
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
+
+        // In debug mode, validate the freed pointer and get back the whole block
+        #[cfg(feature = "heap-debug")]
+        let (ptr, layout) = unsafe { debug::check_and_poison(&allocator, ptr, layout) };
+
+        allocator.statistics.record_dealloc(&layout);
         match block_size_index(&layout) {
             Some(index) => {
                 // Whether or not this region was allocated with the fallback allocator, we
@@ -116,6 +266,7 @@ unsafe impl GlobalAlloc for Mutex<FixedSizeBlockAllocator> {
                     // Set the new block to be the head of the list
                     allocator.list_heads[index] = Some(&mut *new_block_ptr);
                 }
+                allocator.statistics.block_sizes[index].free_list_length += 1;
             }
             None => {
                 // Region is too large, so it must have been allocated by the fallback
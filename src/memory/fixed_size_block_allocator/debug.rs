@@ -0,0 +1,131 @@
+//! Heap corruption detection for the `heap-debug` feature.
+//!
+//! Every allocation is surrounded by redzones filled with a canary byte, and freed blocks are
+//! filled with a poison byte. When memory is freed, the redzones are checked for overflows and
+//! the pointer is checked against the heap bounds and the free lists. Searching the free lists
+//! makes every free linear in the number of cached blocks, so this is only meant for debugging.
+
+use alloc::alloc::Layout;
+use core::{ptr, slice};
+
+use log::error;
+
+use super::{block_size_index, Block, FixedSizeBlockAllocator};
+
+/// The minimum size of the redzones placed before and after each allocation.
+const REDZONE_SIZE: usize = 16;
+/// The byte written to the redzones.
+const CANARY_BYTE: u8 = 0xca;
+/// The byte written to freed memory.
+const POISON_BYTE: u8 = 0xde;
+
+/// Returns the offset of the caller's memory within a guarded block. This is a multiple of the
+/// layout's alignment, since both are powers of two.
+fn front_redzone_size(layout: &Layout) -> usize {
+    REDZONE_SIZE.max(layout.align())
+}
+
+/// Returns the layout of a block that holds the given layout with a redzone on each side.
+pub(super) fn guarded_layout(layout: Layout) -> Layout {
+    let size = front_redzone_size(&layout) + layout.size() + REDZONE_SIZE;
+    Layout::from_size_align(size, layout.align()).expect("guarded layout is too large")
+}
+
+/// Fills the redzones of a newly allocated block and returns the pointer to hand to the caller.
+///
+/// # Safety
+/// `block` must be null or point to a block allocated with `guarded_layout(layout)`.
+pub(super) unsafe fn guard(block: *mut u8, layout: Layout) -> *mut u8 {
+    if block.is_null() {
+        return block;
+    }
+
+    let front = front_redzone_size(&layout);
+    unsafe {
+        block.write_bytes(CANARY_BYTE, front);
+        block
+            .add(front + layout.size())
+            .write_bytes(CANARY_BYTE, REDZONE_SIZE);
+        block.add(front)
+    }
+}
+
+/// Validates a pointer that is being freed, then poisons its block.
+///
+/// Returns the start of the block and its guarded layout, which is what the allocator actually
+/// handed out.
+///
+/// # Panics
+/// Panics after logging the offending layout if the pointer is outside the heap or misaligned,
+/// if the block was already freed, or if one of its redzones was overwritten.
+///
+/// # Safety
+/// If `ptr` passes the bounds checks, it must have been returned by the allocator.
+pub(super) unsafe fn check_and_poison(
+    allocator: &FixedSizeBlockAllocator,
+    ptr: *mut u8,
+    layout: Layout,
+) -> (*mut u8, Layout) {
+    let front = front_redzone_size(&layout);
+    let guarded_layout = guarded_layout(layout);
+    let heap_bottom = allocator.fallback_allocator.bottom();
+    let heap_top = allocator.fallback_allocator.top();
+
+    let addr = ptr as usize;
+    if addr < heap_bottom + front
+        || addr + layout.size() + REDZONE_SIZE > heap_top
+        || !addr.is_multiple_of(layout.align())
+    {
+        report("free of a pointer that was never allocated", ptr, layout);
+    }
+
+    let block = unsafe { ptr.sub(front) };
+    let (front_redzone, back_redzone) = unsafe {
+        (
+            slice::from_raw_parts(block, front),
+            slice::from_raw_parts(ptr.add(layout.size()), REDZONE_SIZE),
+        )
+    };
+
+    // A freed block is poisoned entirely, apart from the free list link at its start
+    if is_in_free_list(allocator, block, &guarded_layout)
+        || back_redzone.iter().all(|&byte| byte == POISON_BYTE)
+    {
+        report("double free", ptr, layout);
+    }
+    if front_redzone.iter().any(|&byte| byte != CANARY_BYTE) {
+        report("buffer underflow detected in front redzone", ptr, layout);
+    }
+    if back_redzone.iter().any(|&byte| byte != CANARY_BYTE) {
+        report("buffer overflow detected in back redzone", ptr, layout);
+    }
+
+    unsafe {
+        block.write_bytes(POISON_BYTE, guarded_layout.size());
+    }
+    (block, guarded_layout)
+}
+
+/// Checks whether the given block is already in the free list for its block size.
+fn is_in_free_list(allocator: &FixedSizeBlockAllocator, block: *mut u8, layout: &Layout) -> bool {
+    let index = match block_size_index(layout) {
+        Some(index) => index,
+        None => return false,
+    };
+    let mut current = allocator.list_heads[index].as_deref();
+    while let Some(free_block) = current {
+        if ptr::eq(free_block, block as *const Block) {
+            return true;
+        }
+        current = free_block.next.as_deref();
+    }
+    false
+}
+
+fn report(problem: &str, ptr: *mut u8, layout: Layout) -> ! {
+    error!(
+        "heap corruption: {} at {:p} (layout: {:?})",
+        problem, ptr, layout
+    );
+    panic!("heap corruption: {} at {:p}", problem, ptr);
+}
@@ -1,11 +1,19 @@
+use core::slice;
+
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 use x86_64::{
     registers::control::Cr3,
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, OffsetPageTable, PageSize, PageTable, PhysFrame,
+        Size1GiB, Size2MiB, Size4KiB,
+    },
     PhysAddr, VirtAddr,
 };
 
-const PAGE_SIZE: usize = 4096;
+use crate::memory::PAGE_SIZE;
+
+/// Number of frames tracked by a single word of the bitmap.
+const BITS_PER_WORD: usize = u64::BITS as usize;
 
 /// Initialize a new OffsetPageTable mapper.
 ///
@@ -21,10 +29,34 @@ pub unsafe fn initialize_mapper(physical_memory_offset: VirtAddr) -> OffsetPageT
     unsafe { OffsetPageTable::new(&mut *page_table_ptr, physical_memory_offset) }
 }
 
-/// A FrameAllocator that returns usable frames from the bootloader's memory map.
+/// A physical frame manager built from the bootloader's memory map.
+///
+/// Frames are tracked in a two-level bitmap: the lower level holds one bit per frame, and the
+/// upper level holds one bit per lower-level word that still contains a free frame. A set bit
+/// means "free" at both levels. One upper-level word covers 64 lower-level words, i.e. 4096
+/// frames (16 MiB) of physical memory. Deallocation runs in constant time, and finding a free
+/// frame is still linear in the number of upper-level words, but it starts at the lowest word
+/// that may have a free frame and skips 16 MiB per word. The bitmap itself is stored in the first usable region large enough to hold
+/// it and is accessed through the physical memory mapping.
+///
+/// A second bitmap of the same size records which frames are managed at all, i.e. were usable
+/// in the memory map, so that frames of the kernel, the bootloader or the firmware can never
+/// end up in the pool.
+///
+/// Frames can be shared between several owners, e.g. by copy-on-write mappings. Next to the
+/// bitmap, the allocator keeps a count of the additional owners of every frame, and
+/// deallocating a shared frame only drops one reference.
 pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+    bitmap: &'static mut [u64],
+    /// A set bit means the frame was usable in the memory map and doesn't hold the bitmaps.
+    managed: &'static mut [u64],
+    summary: &'static mut [u64],
+    /// The number of owners of each allocated frame beyond the first.
+    shares: &'static mut [u16],
+    /// Index of the first summary word that may contain a set bit.
+    next_summary: usize,
+    total_frames: usize,
+    free_frames: usize,
 }
 
 impl BootInfoFrameAllocator {
@@ -33,32 +65,335 @@ impl BootInfoFrameAllocator {
     /// # Safety
     /// This constructor is unsafe because the caller must guarantee that the passed
     /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
-    pub unsafe fn new(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
-        }
-    }
+    /// as `USABLE` in it are really unused. The complete physical memory must also be
+    /// mapped at `physical_memory_offset`.
+    pub unsafe fn new(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let frame_count = memory_map
+            .iter()
+            .filter(|region| region.region_type == MemoryRegionType::Usable)
+            .map(|region| region.range.end_addr() as usize / PAGE_SIZE)
+            .max()
+            .unwrap_or(0);
+        let bitmap_words = frame_count.div_ceil(BITS_PER_WORD);
+        let summary_words = bitmap_words.div_ceil(BITS_PER_WORD);
+        let storage_size = (2 * bitmap_words + summary_words) * 8 + frame_count * 2;
 
-    // Map usable memory regions to physical frames by breaking the address ranges
-    // into page-sized chunks.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        self.memory_map
+        // Steal enough frames from the start of the first usable region that can hold the bitmap
+        let storage_start = memory_map
             .iter()
             .filter(|region| region.region_type == MemoryRegionType::Usable)
-            .map(|usable_region| usable_region.range.start_addr()..usable_region.range.end_addr())
-            // Divide regions into page-sized chunks (may leave extra bytes at end of some regions)
-            .flat_map(|range| range.step_by(PAGE_SIZE))
-            // Yield physical frames corresponding to start of each chunk
-            .map(|address| PhysFrame::containing_address(PhysAddr::new(address)))
+            .find(|region| {
+                region.range.end_addr() - region.range.start_addr() >= storage_size as u64
+            })
+            .map(|region| region.range.start_addr())
+            .expect("no usable region is large enough to hold the frame bitmap");
+        let storage_ptr: *mut u64 = (physical_memory_offset + storage_start).as_mut_ptr();
+        let (bitmap, managed, summary, shares) = unsafe {
+            // Every frame starts out as used and unmanaged until the memory map tells us otherwise
+            storage_ptr.write_bytes(0, 2 * bitmap_words + summary_words);
+            let shares_ptr = storage_ptr
+                .add(2 * bitmap_words + summary_words)
+                .cast::<u16>();
+            shares_ptr.write_bytes(0, frame_count);
+            (
+                slice::from_raw_parts_mut(storage_ptr, bitmap_words),
+                slice::from_raw_parts_mut(storage_ptr.add(bitmap_words), bitmap_words),
+                slice::from_raw_parts_mut(storage_ptr.add(2 * bitmap_words), summary_words),
+                slice::from_raw_parts_mut(shares_ptr, frame_count),
+            )
+        };
+
+        let mut allocator = BootInfoFrameAllocator {
+            bitmap,
+            managed,
+            summary,
+            shares,
+            next_summary: 0,
+            total_frames: 0,
+            free_frames: 0,
+        };
+
+        let storage_end = storage_start as usize + storage_size.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        for region in memory_map
+            .iter()
+            .filter(|region| region.region_type == MemoryRegionType::Usable)
+        {
+            let start = region.range.start_addr() as usize / PAGE_SIZE;
+            let end = region.range.end_addr() as usize / PAGE_SIZE;
+            for index in start..end {
+                allocator.total_frames += 1;
+                let address = index * PAGE_SIZE;
+                if address < storage_start as usize || address >= storage_end {
+                    allocator.managed[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+                    allocator.mark_free(index);
+                }
+            }
+        }
+
+        allocator
+    }
+
+    /// The number of usable frames managed by this allocator.
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// The number of frames that are currently available for allocation.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// The number of frames that are currently allocated, including those holding the bitmap.
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    /// Adds an owner to an allocated frame, so that it is only freed once every owner has
+    /// deallocated it.
+    pub fn share_frame(&mut self, frame: PhysFrame) {
+        let index = frame.start_address().as_u64() as usize / PAGE_SIZE;
+        assert!(
+            self.is_managed(index) && !self.is_free(index),
+            "shared frame {:?} is not allocated",
+            frame
+        );
+        self.shares[index] = self.shares[index]
+            .checked_add(1)
+            .expect("too many owners of a shared frame");
+    }
+
+    /// The number of owners of the frame, which is zero if it is free or not managed by this
+    /// allocator.
+    pub fn reference_count(&self, frame: PhysFrame) -> usize {
+        let index = frame.start_address().as_u64() as usize / PAGE_SIZE;
+        if !self.is_managed(index) || self.is_free(index) {
+            return 0;
+        }
+        usize::from(self.shares[index]) + 1
+    }
+
+    /// Allocates `count` consecutive frames and returns the first one.
+    ///
+    /// The index of the first frame is a multiple of `align`, the frames don't cross a multiple
+    /// of `boundary` unless it is zero, and the frame indexes stay below `limit`. All three are
+    /// in frames, and `align` and `boundary` must be powers of two.
+    pub fn allocate_contiguous(
+        &mut self,
+        count: usize,
+        align: usize,
+        boundary: usize,
+        limit: usize,
+    ) -> Option<PhysFrame> {
+        assert!(align.is_power_of_two() && (boundary == 0 || boundary.is_power_of_two()));
+        if count == 0 || (boundary != 0 && count > boundary) {
+            return None;
+        }
+
+        let limit = limit.min(self.bitmap.len() * BITS_PER_WORD);
+        let mut start: usize = 0;
+        loop {
+            start = start.next_multiple_of(align);
+            if start.checked_add(count).is_none_or(|end| end > limit) {
+                return None;
+            }
+            if boundary != 0 && start / boundary != (start + count - 1) / boundary {
+                start = (start / boundary + 1) * boundary;
+                continue;
+            }
+            // Skip words without any free frame at once
+            if self.bitmap[start / BITS_PER_WORD] == 0 {
+                start = (start / BITS_PER_WORD + 1) * BITS_PER_WORD;
+                continue;
+            }
+            match (start..start + count)
+                .rev()
+                .find(|&index| !self.is_free(index))
+            {
+                Some(used) => start = used + 1,
+                None => break,
+            }
+        }
+
+        for index in start..start + count {
+            self.mark_used(index);
+        }
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            (start * PAGE_SIZE) as u64,
+        )))
+    }
+
+    /// Frees `count` consecutive frames starting at `start`.
+    ///
+    /// # Safety
+    /// The frames must have been allocated and must not be in use anymore.
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        for frame in PhysFrame::range(start, start + count as u64) {
+            unsafe { self.deallocate_frame(frame) };
+        }
+    }
+
+    /// Returns whether the frame with the given index was usable in the memory map and can be
+    /// handed out by this allocator.
+    fn is_managed(&self, index: usize) -> bool {
+        index < self.shares.len()
+            && self.managed[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    /// Returns whether the frame with the given index is free.
+    fn is_free(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn mark_free(&mut self, index: usize) {
+        let word = index / BITS_PER_WORD;
+        self.bitmap[word] |= 1 << (index % BITS_PER_WORD);
+        self.summary[word / BITS_PER_WORD] |= 1 << (word % BITS_PER_WORD);
+        self.next_summary = self.next_summary.min(word / BITS_PER_WORD);
+        self.free_frames += 1;
+    }
+
+    fn mark_used(&mut self, index: usize) {
+        let word = index / BITS_PER_WORD;
+        self.bitmap[word] &= !(1 << (index % BITS_PER_WORD));
+        if self.bitmap[word] == 0 {
+            self.summary[word / BITS_PER_WORD] &= !(1 << (word % BITS_PER_WORD));
+        }
+        self.free_frames -= 1;
+    }
+
+    /// Finds the index of the lowest free frame, if any.
+    fn find_free(&mut self) -> Option<usize> {
+        let summary_index =
+            (self.next_summary..self.summary.len()).find(|&index| self.summary[index] != 0)?;
+        self.next_summary = summary_index;
+        let word =
+            summary_index * BITS_PER_WORD + self.summary[summary_index].trailing_zeros() as usize;
+        Some(word * BITS_PER_WORD + self.bitmap[word].trailing_zeros() as usize)
     }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        let index = self.find_free()?;
+        self.mark_used(index);
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            (index * PAGE_SIZE) as u64,
+        )))
+    }
+}
+
+/// Huge frames are taken from the bitmap as runs of aligned 4 KiB frames.
+unsafe impl FrameAllocator<Size2MiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let count = (Size2MiB::SIZE / Size4KiB::SIZE) as usize;
+        let start = self.allocate_contiguous(count, count, 0, usize::MAX)?;
+        Some(PhysFrame::containing_address(start.start_address()))
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        let count = (Size1GiB::SIZE / Size4KiB::SIZE) as usize;
+        let start = self.allocate_contiguous(count, count, 0, usize::MAX)?;
+        Some(PhysFrame::containing_address(start.start_address()))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let start = PhysFrame::containing_address(frame.start_address());
+        let count = (Size2MiB::SIZE / Size4KiB::SIZE) as usize;
+        unsafe { self.deallocate_contiguous(start, count) };
+    }
+}
+
+impl FrameDeallocator<Size1GiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size1GiB>) {
+        let start = PhysFrame::containing_address(frame.start_address());
+        let count = (Size1GiB::SIZE / Size4KiB::SIZE) as usize;
+        unsafe { self.deallocate_contiguous(start, count) };
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    /// Drops one owner of the frame, and frees it once no owners are left.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = frame.start_address().as_u64() as usize / PAGE_SIZE;
+        assert!(
+            self.is_managed(index),
+            "deallocated frame {:?} is not managed by this allocator",
+            frame
+        );
+        assert!(!self.is_free(index), "double free of frame {:?}", frame);
+        if self.shares[index] > 0 {
+            self.shares[index] -= 1;
+        } else {
+            self.mark_free(index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_deallocated_frame_is_reused() {
+        let mut allocator = crate::memory::frame_allocator().lock();
+        let free_frames = allocator.free_frames();
+
+        let frame = allocator.allocate_frame().unwrap();
+        assert_eq!(allocator.free_frames(), free_frames - 1);
+        unsafe { allocator.deallocate_frame(frame) };
+        assert_eq!(allocator.free_frames(), free_frames);
+
+        // The lowest free frame is always handed out first
+        assert_eq!(allocator.allocate_frame(), Some(frame));
+        unsafe { allocator.deallocate_frame(frame) };
+    }
+
+    #[test_case]
+    fn test_shared_frame_is_freed_by_last_owner() {
+        let mut allocator = crate::memory::frame_allocator().lock();
+        let free_frames = allocator.free_frames();
+
+        let frame = allocator.allocate_frame().unwrap();
+        allocator.share_frame(frame);
+        assert_eq!(allocator.reference_count(frame), 2);
+        unsafe { allocator.deallocate_frame(frame) };
+        assert_eq!(allocator.reference_count(frame), 1);
+        assert_eq!(allocator.free_frames(), free_frames - 1);
+        unsafe { allocator.deallocate_frame(frame) };
+        assert_eq!(allocator.reference_count(frame), 0);
+        assert_eq!(allocator.free_frames(), free_frames);
+    }
+
+    #[test_case]
+    fn test_frames_outside_usable_memory_are_not_managed() {
+        let allocator = crate::memory::frame_allocator().lock();
+        // The bootloader allocated the level 4 table outside of the usable regions
+        let (level_4_frame, _) = Cr3::read();
+        assert_eq!(allocator.reference_count(level_4_frame), 0);
+        let index = level_4_frame.start_address().as_u64() as usize / PAGE_SIZE;
+        assert!(!allocator.is_managed(index));
+        assert!(allocator.free_frames() <= allocator.total_frames());
+    }
+
+    #[test_case]
+    fn test_contiguous_frames_respect_constraints() {
+        let mut allocator = crate::memory::frame_allocator().lock();
+        let free_frames = allocator.free_frames();
+
+        // 3 frames aligned to 2 frames, within a 4 frame boundary, below 16 MiB
+        let start = allocator.allocate_contiguous(3, 2, 4, 4096).unwrap();
+        let index = start.start_address().as_u64() as usize / PAGE_SIZE;
+        assert_eq!(index % 2, 0);
+        assert_eq!(index / 4, (index + 2) / 4);
+        assert!(index + 3 <= 4096);
+        assert_eq!(allocator.free_frames(), free_frames - 3);
+
+        unsafe { allocator.deallocate_contiguous(start, 3) };
+        assert_eq!(allocator.free_frames(), free_frames);
+        assert!(allocator.allocate_contiguous(5, 1, 4, usize::MAX).is_none());
     }
 }
@@ -1,12 +1,42 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use log::warn;
 use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
-    },
+    structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Page, Size2MiB, Size4KiB},
     VirtAddr,
 };
 
-pub const HEAP_START: usize = 0x4444_4444_0000;
+use crate::memory::{
+    align_up,
+    layout::{HEAP_AREA_SIZE, HEAP_START},
+    vmalloc, DATA_FLAGS, HUGE_PAGE_SIZE, PAGE_SIZE,
+};
+
+/// The size of the heap when it is first initialized.
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// The default limit on the size the heap may grow to.
+///
+/// Growing the heap needs the mapper and the frame allocator, so code holding either lock must
+/// not allocate: an allocation that needs more heap fails meanwhile.
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024; // 16 MiB
+
+/// The heap is grown by at least this many bytes at a time to avoid mapping pages one by one.
+const HEAP_GROWTH_INCREMENT: usize = 64 * 1024; // 64 KiB
+
+static HEAP_SIZE_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
+/// Whether a warning was logged because the heap couldn't grow while the paging locks were
+/// held. It is only logged once, so that a failing allocation loop doesn't flood the log.
+static CONTENTION_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Sets the size the heap may grow to. This doesn't shrink a heap that is already larger, and
+/// the heap never grows beyond its area of the address space.
+///
+/// The heap only grows while neither the mapper nor the frame allocator is locked, so callers
+/// holding either lock must not allocate, whatever the limit is.
+pub fn set_heap_size_limit(limit: usize) {
+    HEAP_SIZE_LIMIT.store(limit.min(HEAP_AREA_SIZE), Ordering::Relaxed);
+}
 
 pub(crate) fn initialize(
     mapper: &mut impl Mapper<Size4KiB>,
@@ -21,12 +51,90 @@ pub(crate) fn initialize(
     };
 
     for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        map_page(mapper, frame_allocator, page)?;
+    }
+
+    Ok(())
+}
+
+/// Maps more memory directly after `heap_end` so that the heap can grow by at least
+/// `min_additional` bytes.
+///
+/// Returns the number of bytes that were mapped, which is zero if the heap is already at its
+/// size limit or no frames are left. The caller is responsible for handing the new memory to
+/// its allocator.
+///
+/// This is called by the global allocator, so the mapper or the frame allocator may already be
+/// locked, by the code that is allocating or by the code an interrupt handler interrupted.
+/// Rather than spinning forever, the heap doesn't grow then and the allocation fails, which is
+/// logged the first time it happens.
+pub(crate) fn grow(heap_end: usize, min_additional: usize) -> usize {
+    let limit = HEAP_START + HEAP_SIZE_LIMIT.load(Ordering::Relaxed);
+    if heap_end < HEAP_START || heap_end >= limit || !heap_end.is_multiple_of(PAGE_SIZE) {
+        return 0;
+    }
+
+    let additional = align_up(min_additional.max(HEAP_GROWTH_INCREMENT), PAGE_SIZE);
+    let additional = additional.min(limit - heap_end);
+    if additional < min_additional {
+        return 0;
+    }
+
+    let (mut mapper, mut frame_allocator) = match (
+        crate::memory::mapper().try_lock(),
+        crate::memory::frame_allocator().try_lock(),
+    ) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => {
+            if !CONTENTION_WARNED.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "heap can't grow by {} bytes while the mapper or frame allocator is locked",
+                    min_additional
+                );
+            }
+            return 0;
+        }
+    };
+    let mut mapped = 0;
+    // If we run out of frames partway through, the pages mapped so far are still usable
+    while mapped < additional {
+        let address = VirtAddr::new((heap_end + mapped) as u64);
+        // Large growth steps use huge pages where the heap end is aligned for them
+        if address.is_aligned(HUGE_PAGE_SIZE as u64)
+            && additional - mapped >= HUGE_PAGE_SIZE
+            && vmalloc::map_huge_page::<Size2MiB>(
+                &mut mapper,
+                &mut frame_allocator,
+                address,
+                None,
+                DATA_FLAGS,
+            ) == Ok(true)
+        {
+            mapped += HUGE_PAGE_SIZE;
+            continue;
+        }
+
+        let page = Page::containing_address(address);
+        if map_page(&mut *mapper, &mut *frame_allocator, page).is_err() {
+            break;
+        }
+        mapped += PAGE_SIZE;
     }
+    mapped
+}
 
+fn map_page(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    page: Page,
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    unsafe {
+        mapper
+            .map_to(page, frame, DATA_FLAGS, frame_allocator)?
+            .flush()
+    };
     Ok(())
 }
@@ -0,0 +1,166 @@
+use conquer_once::spin::OnceCell;
+use x86_64::VirtAddr;
+
+/// User space takes up whole level 4 entries, which are private to each address space.
+pub const USER_START: usize = 0x1000_0000_0000;
+pub const USER_SIZE: usize = 0x1000_0000_0000; // 16 TiB
+
+pub const HEAP_START: usize = 0x4444_4444_0000;
+/// The heap can never grow past this size, whatever its size limit.
+pub const HEAP_AREA_SIZE: usize = 4 * 1024 * 1024 * 1024; // 4 GiB
+
+pub const VMALLOC_START: usize = 0x5555_0000_0000;
+pub const VMALLOC_SIZE: usize = 64 * 1024 * 1024 * 1024; // 64 GiB
+
+pub const KERNEL_STACKS_START: usize = 0x5580_0000_0000;
+pub const KERNEL_STACKS_SIZE: usize = 1024 * 1024 * 1024; // 1 GiB
+
+pub const MMIO_START: usize = 0x5600_0000_0000;
+pub const MMIO_SIZE: usize = 64 * 1024 * 1024 * 1024; // 64 GiB
+
+/// What an area of the kernel address space is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AreaKind {
+    /// Memory of the current [`AddressSpace`](crate::memory::AddressSpace).
+    User,
+    /// The complete physical memory, mapped by the bootloader.
+    PhysicalMemory,
+    Heap,
+    /// General purpose regions handed out by [`vmalloc`](crate::memory::vmalloc).
+    Vmalloc,
+    KernelStacks,
+    /// Mappings of device memory.
+    Mmio,
+}
+
+impl AreaKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::PhysicalMemory => "physical memory",
+            Self::Heap => "heap",
+            Self::Vmalloc => "vmalloc",
+            Self::KernelStacks => "kernel stacks",
+            Self::Mmio => "MMIO",
+        }
+    }
+}
+
+/// A range of the kernel address space that is reserved for one purpose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Area {
+    pub kind: AreaKind,
+    pub start: VirtAddr,
+    /// The size of the area in bytes.
+    pub size: u64,
+}
+
+impl Area {
+    fn fixed(kind: AreaKind, start: usize, size: usize) -> Self {
+        Self {
+            kind,
+            start: VirtAddr::new(start as u64),
+            size: size as u64,
+        }
+    }
+
+    /// The end of the area, exclusive.
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    /// Returns whether the area contains the given address.
+    pub fn contains(&self, address: VirtAddr) -> bool {
+        self.start <= address && address < self.end()
+    }
+
+    fn overlaps(&self, other: &Area) -> bool {
+        self.start < other.end() && other.start < self.end()
+    }
+}
+
+/// The areas at addresses chosen by the kernel.
+fn fixed_areas() -> [Area; 5] {
+    [
+        Area::fixed(AreaKind::User, USER_START, USER_SIZE),
+        Area::fixed(AreaKind::Heap, HEAP_START, HEAP_AREA_SIZE),
+        Area::fixed(AreaKind::Vmalloc, VMALLOC_START, VMALLOC_SIZE),
+        Area::fixed(
+            AreaKind::KernelStacks,
+            KERNEL_STACKS_START,
+            KERNEL_STACKS_SIZE,
+        ),
+        Area::fixed(AreaKind::Mmio, MMIO_START, MMIO_SIZE),
+    ]
+}
+
+/// The physical memory mapping, which is placed by the bootloader.
+static PHYSICAL_MEMORY_AREA: OnceCell<Area> = OnceCell::uninit();
+
+/// Records where the bootloader mapped the physical memory and checks that no two areas of the
+/// layout overlap.
+///
+/// # Panics
+/// Panics if the areas overlap or aren't page aligned.
+pub(crate) fn initialize(physical_memory_offset: VirtAddr, physical_memory_size: u64) {
+    PHYSICAL_MEMORY_AREA.init_once(|| Area {
+        kind: AreaKind::PhysicalMemory,
+        start: physical_memory_offset,
+        size: physical_memory_size,
+    });
+
+    for (index, area) in areas().enumerate() {
+        assert!(
+            area.start.is_aligned(4096u64) && area.size % 4096 == 0,
+            "{} area is not page aligned",
+            area.kind.name()
+        );
+        if let Some(other) = areas().skip(index + 1).find(|other| area.overlaps(other)) {
+            panic!(
+                "{} area {:?} overlaps {} area {:?}",
+                area.kind.name(),
+                area.start..area.end(),
+                other.kind.name(),
+                other.start..other.end()
+            );
+        }
+    }
+}
+
+/// Returns every area of the kernel address space. The physical memory area is only included
+/// once the memory subsystem is initialized.
+pub fn areas() -> impl Iterator<Item = Area> {
+    IntoIterator::into_iter(fixed_areas()).chain(PHYSICAL_MEMORY_AREA.get().copied())
+}
+
+/// Returns the area of the given kind.
+///
+/// # Panics
+/// Panics if the physical memory area is requested before the memory subsystem is initialized.
+pub fn area(kind: AreaKind) -> Area {
+    areas()
+        .find(|area| area.kind == kind)
+        .expect("physical memory area is uninitialized")
+}
+
+/// Returns the area that contains the given address, if any.
+pub fn area_containing(address: VirtAddr) -> Option<Area> {
+    areas().find(|area| area.contains(address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_area_lookup() {
+        let heap = area_containing(VirtAddr::new(HEAP_START as u64)).unwrap();
+        assert_eq!(heap.kind, AreaKind::Heap);
+
+        let physical_memory_offset = crate::memory::physical_memory_offset();
+        let physical_memory = area_containing(physical_memory_offset).unwrap();
+        assert_eq!(physical_memory.kind, AreaKind::PhysicalMemory);
+
+        assert!(area_containing(VirtAddr::new(0)).is_none());
+    }
+}
@@ -1,7 +1,10 @@
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{mem, ptr};
 
-use crate::{memory::align_up, sync::Mutex};
+use crate::{
+    memory::{align_up, heap},
+    sync::Mutex,
+};
 
 struct FreeListNode {
     size: usize,
@@ -22,20 +25,37 @@ impl FreeListNode {
     }
 }
 
+/// The strategy used to pick a free region for an allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitPolicy {
+    /// Use the first suitable region, starting from the lowest address.
+    FirstFit,
+    /// Use the smallest suitable region, which keeps large regions intact for longer.
+    BestFit,
+}
+
 /// A heap allocator that uses a linked list to keep track of freed memory regions.
 ///
-/// This allocator eventually creates many small free regions without merging adjacent
-/// free regions, which causes fragmentation. Using the `LockedHeap` type from the
-/// `linked_list_allocator` crate is recommended instead.
+/// The list is kept sorted by address, and freed regions are merged with their free neighbours
+/// to limit fragmentation.
 pub struct LinkedListAllocator {
     head: FreeListNode,
+    policy: FitPolicy,
+    heap_end: usize,
 }
 
 impl LinkedListAllocator {
-    /// Creates an empty LinkedListAllocator.
+    /// Creates an empty LinkedListAllocator that uses the first-fit policy.
     pub const fn new() -> Self {
+        Self::with_policy(FitPolicy::FirstFit)
+    }
+
+    /// Creates an empty LinkedListAllocator that uses the given fit policy.
+    pub const fn with_policy(policy: FitPolicy) -> Self {
         Self {
             head: FreeListNode::new(0),
+            policy,
+            heap_end: 0,
         }
     }
 
@@ -45,25 +65,80 @@ impl LinkedListAllocator {
     /// This method is unsafe because the caller must guarantee that the given heap bounds
     /// are valid and that the heap is unused. This method must be called only once.
     pub unsafe fn initialize(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_end = heap_start + heap_size;
         unsafe {
             self.free_region(heap_start, heap_size);
         }
     }
 
-    /// Adds the given memory region to the front of the list.
+    /// Maps more memory at the end of the heap and adds it to the list.
+    ///
+    /// Returns whether the heap was grown.
+    fn grow(&mut self, size: usize, align: usize) -> bool {
+        let added = heap::grow(self.heap_end, size + align);
+        if added > 0 {
+            unsafe {
+                self.free_region(self.heap_end, added);
+            }
+            self.heap_end += added;
+        }
+        added > 0
+    }
+
+    /// Inserts the given memory region into the list, merging it with adjacent free regions.
     unsafe fn free_region(&mut self, addr: usize, size: usize) {
         // ensure that the freed region is capable of holding ListNode
         assert_eq!(align_up(addr, mem::align_of::<FreeListNode>()), addr);
         assert!(size >= mem::size_of::<FreeListNode>());
 
+        // Find the last region that starts before the freed one, so the list stays sorted. The
+        // head node has a size of zero, which no real region can have.
+        let mut current = &mut self.head;
+        while current
+            .next
+            .as_ref()
+            .is_some_and(|next| next.start_addr() < addr)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+        assert!(
+            current.size == 0 || current.end_addr() <= addr,
+            "freed region at {:#x} overlaps a free region",
+            addr
+        );
+
         let mut node = FreeListNode::new(size);
-        // break off list at item 1, attach it to new node
-        node.next = self.head.next.take();
-        // write new node to start of freed region
-        let node_ptr = addr as *mut FreeListNode;
-        unsafe {
-            node_ptr.write(node);
-            self.head.next = Some(&mut *node_ptr)
+        node.next = current.next.take();
+        if let Some(next) = node.next.as_ref() {
+            assert!(
+                addr + size <= next.start_addr(),
+                "freed region at {:#x} overlaps a free region",
+                addr
+            );
+        }
+
+        // Merge with the following region
+        if node
+            .next
+            .as_ref()
+            .is_some_and(|next| next.start_addr() == addr + size)
+        {
+            let next = node.next.take().unwrap();
+            node.size += next.size;
+            node.next = next.next.take();
+        }
+
+        // Merge with the preceding region, or link in a new node
+        if current.size > 0 && current.end_addr() == addr {
+            current.size += node.size;
+            current.next = node.next;
+        } else {
+            // write new node to start of freed region
+            let node_ptr = addr as *mut FreeListNode;
+            unsafe {
+                node_ptr.write(node);
+                current.next = Some(&mut *node_ptr)
+            }
         }
     }
 
@@ -76,28 +151,43 @@ impl LinkedListAllocator {
         size: usize,
         align: usize,
     ) -> Option<(&'static mut FreeListNode, usize)> {
+        let region_start = self.find_region(size, align)?;
+
         let mut current = &mut self.head;
-        // look for a large enough memory region in linked list
-        while let Some(ref mut region) = current.next {
-            if Self::is_suitable_region(&region, size, align) {
-                // get pointer to the FreeListNode after the suitable region
-                let next_free_node = region.next.take();
-                // get aligned address to start of new region
-                let aligned_start_addr = align_up(region.start_addr(), align);
-                // get pointer to suitable region's FreeListNode
-                let suitable_region_details =
-                    Some((current.next.take().unwrap(), aligned_start_addr));
-                // skip over the now-removed node
-                current.next = next_free_node;
-                return suitable_region_details;
-            } else {
-                // try next region
-                current = current.next.as_mut().unwrap();
+        while current
+            .next
+            .as_ref()
+            .is_some_and(|next| next.start_addr() != region_start)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+        // unlink the region from the list
+        let region = current.next.take().unwrap();
+        current.next = region.next.take();
+
+        Some((region, align_up(region_start, align)))
+    }
+
+    /// Returns the start address of the region chosen for an allocation with the given size and
+    /// alignment, according to the fit policy.
+    fn find_region(&self, size: usize, align: usize) -> Option<usize> {
+        let mut best_region: Option<&FreeListNode> = None;
+        let mut current = self.head.next.as_deref();
+        while let Some(region) = current {
+            if Self::is_suitable_region(region, size, align) {
+                match self.policy {
+                    FitPolicy::FirstFit => return Some(region.start_addr()),
+                    FitPolicy::BestFit => {
+                        if best_region.is_none_or(|best| region.size < best.size) {
+                            best_region = Some(region);
+                        }
+                    }
+                }
             }
+            current = region.next.as_deref();
         }
 
-        // no suitable region found
-        None
+        best_region.map(FreeListNode::start_addr)
     }
 
     /// Determines whether to use the given region for an allocation with a given size and
@@ -116,6 +206,12 @@ impl LinkedListAllocator {
             return false;
         }
 
+        // If aligning the start address leaves a gap, can the gap hold a FreeListNode?
+        let padding = start_addr - region.start_addr();
+        if padding > 0 && padding < mem::size_of::<FreeListNode>() {
+            return false;
+        }
+
         // If there is leftover space, can we fit a FreeListNode in it, assuming we choose this
         // region?
         let excess_size = region.end_addr() - end_addr;
@@ -147,14 +243,24 @@ unsafe impl GlobalAlloc for Mutex<LinkedListAllocator> {
         let (size, align) = LinkedListAllocator::size_align(layout);
         let mut allocator = self.lock();
 
-        if let Some((region, alloc_start)) = allocator.allocate_region(size, align) {
+        let mut region = allocator.allocate_region(size, align);
+        if region.is_none() && allocator.grow(size, align) {
+            region = allocator.allocate_region(size, align);
+        }
+
+        if let Some((region, alloc_start)) = region {
+            let (region_start, region_end) = (region.start_addr(), region.end_addr());
             // We already checked for overflow in is_suitable_region
             let alloc_end = alloc_start + size;
-            let excess_size = region.end_addr() - alloc_end;
-            if excess_size > 0 {
-                // We know leftover space is big enough to hold a FreeListNode
+            // We know any leftover space is big enough to hold a FreeListNode
+            if alloc_start > region_start {
                 unsafe {
-                    allocator.free_region(alloc_end, excess_size);
+                    allocator.free_region(region_start, alloc_start - region_start);
+                }
+            }
+            if region_end > alloc_end {
+                unsafe {
+                    allocator.free_region(alloc_end, region_end - alloc_end);
                 }
             }
             alloc_start as *mut u8
@@ -169,3 +275,79 @@ unsafe impl GlobalAlloc for Mutex<LinkedListAllocator> {
         unsafe { self.lock().free_region(ptr as usize, size) }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+
+    use super::*;
+
+    const HEAP_SIZE: usize = 4096;
+
+    #[repr(align(4096))]
+    struct TestHeap([u8; HEAP_SIZE]);
+
+    fn allocator_with_policy(policy: FitPolicy) -> Mutex<LinkedListAllocator> {
+        let heap = Box::leak(Box::new(TestHeap([0; HEAP_SIZE])));
+        let mut allocator = LinkedListAllocator::with_policy(policy);
+        unsafe { allocator.initialize(heap as *mut TestHeap as usize, HEAP_SIZE) };
+        Mutex::new(allocator)
+    }
+
+    fn free_regions(allocator: &Mutex<LinkedListAllocator>) -> usize {
+        let allocator = allocator.lock();
+        let mut count = 0;
+        let mut current = allocator.head.next.as_deref();
+        while let Some(region) = current {
+            count += 1;
+            current = region.next.as_deref();
+        }
+        count
+    }
+
+    #[test_case]
+    fn test_linked_list_merges_freed_regions() {
+        let allocator = allocator_with_policy(FitPolicy::FirstFit);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let ptrs = [(); 3].map(|_| unsafe { allocator.alloc(layout) });
+
+        unsafe {
+            allocator.dealloc(ptrs[1], layout);
+            allocator.dealloc(ptrs[0], layout);
+            allocator.dealloc(ptrs[2], layout);
+        }
+        assert_eq!(free_regions(&allocator), 1);
+
+        // The whole heap is available again
+        let layout = Layout::from_size_align(HEAP_SIZE, 8).unwrap();
+        assert!(!unsafe { allocator.alloc(layout) }.is_null());
+    }
+
+    #[test_case]
+    fn test_linked_list_fit_policies() {
+        for &policy in &[FitPolicy::FirstFit, FitPolicy::BestFit] {
+            let allocator = allocator_with_policy(policy);
+            let large = Layout::from_size_align(256, 8).unwrap();
+            let small = Layout::from_size_align(128, 8).unwrap();
+            let separator = Layout::from_size_align(64, 8).unwrap();
+            let ptrs = unsafe {
+                [
+                    allocator.alloc(large),
+                    allocator.alloc(separator),
+                    allocator.alloc(small),
+                    allocator.alloc(separator),
+                ]
+            };
+            unsafe {
+                allocator.dealloc(ptrs[0], large);
+                allocator.dealloc(ptrs[2], small);
+            }
+
+            let ptr = unsafe { allocator.alloc(Layout::from_size_align(100, 8).unwrap()) };
+            match policy {
+                FitPolicy::FirstFit => assert_eq!(ptr, ptrs[0]),
+                FitPolicy::BestFit => assert_eq!(ptr, ptrs[2]),
+            }
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use core::fmt;
+
+use log::info;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{PageTable, PageTableFlags, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+/// Flags that the CPU updates on its own, which would otherwise split up contiguous runs.
+const VOLATILE_FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PageTableFlags::ACCESSED.bits() | PageTableFlags::DIRTY.bits(),
+);
+
+/// Flags that only take effect if they are set at every level of the page table.
+const RESTRICTIVE_FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PageTableFlags::WRITABLE.bits() | PageTableFlags::USER_ACCESSIBLE.bits(),
+);
+
+/// The number of bytes mapped by a single entry at each page table level, from level 4 down.
+const ENTRY_SIZES: [u64; 4] = [512 << 30, 1 << 30, 2 << 20, 4 << 10];
+
+/// The end of the 48-bit virtual address space, before sign extension.
+const ADDRESS_SPACE_END: u64 = 1 << 48;
+
+/// A run of virtual memory that is mapped contiguously to physical memory with the same page
+/// size and flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub start: VirtAddr,
+    /// The size of the run in bytes.
+    pub size: u64,
+    /// The physical address that `start` is mapped to.
+    pub physical_start: PhysAddr,
+    /// The size of the pages in the run: 4 KiB, 2 MiB or 1 GiB.
+    pub page_size: u64,
+    /// The effective flags of the run. `WRITABLE` and `USER_ACCESSIBLE` are only set if every
+    /// level of the page table allows them, and `NO_EXECUTE` is set if any level sets it.
+    /// `ACCESSED` and `DIRTY` are left out, and so is `HUGE_PAGE` for large pages. For 4 KiB
+    /// pages, the `HUGE_PAGE` bit is the PAT bit and is kept.
+    pub flags: PageTableFlags,
+}
+
+impl Mapping {
+    /// The end of the run, exclusive. For a run that ends at the top of the lower half, this is
+    /// the start of the higher half, and for one that ends at the top of the higher half, it
+    /// wraps around to zero.
+    pub fn end(&self) -> VirtAddr {
+        VirtAddr::new_truncate(self.start.as_u64().wrapping_add(self.size))
+    }
+
+    /// Returns whether the run contains the given address.
+    pub fn contains(&self, address: VirtAddr) -> bool {
+        // The offset can't overflow like the end can
+        address.as_u64().wrapping_sub(self.start.as_u64()) < self.size
+    }
+
+    /// Returns whether `next` continues this run directly.
+    fn is_continued_by(&self, next: &Mapping) -> bool {
+        // Compare the raw addresses, so runs don't continue across the non-canonical hole
+        self.start.as_u64().checked_add(self.size) == Some(next.start.as_u64())
+            && self.physical_start + self.size == next.physical_start
+            && self.page_size == next.page_size
+            && self.flags == next.flags
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let page_size = match self.page_size {
+            0x1000 => "4K",
+            0x20_0000 => "2M",
+            _ => "1G",
+        };
+        let flag = |flag, c| if self.flags.contains(flag) { c } else { '-' };
+        write!(
+            f,
+            "{:#018x}-{:#018x} {:#014x} {:>12} {} {}{}{}{}",
+            self.start.as_u64(),
+            self.end().as_u64(),
+            self.physical_start.as_u64(),
+            self.size,
+            page_size,
+            flag(PageTableFlags::WRITABLE, 'w'),
+            if self.flags.contains(PageTableFlags::NO_EXECUTE) {
+                '-'
+            } else {
+                'x'
+            },
+            flag(PageTableFlags::USER_ACCESSIBLE, 'u'),
+            flag(PageTableFlags::GLOBAL, 'g'),
+        )
+    }
+}
+
+/// An iterator over the mappings of a level 4 page table, in order of virtual address.
+///
+/// The tables are read through the physical memory mapping without taking the mapper lock, so
+/// the iterator can be used while allocating. Mappings that change during the walk may or may not
+/// be reported.
+pub struct Mappings {
+    level_4_frame: PhysFrame,
+    physical_memory_offset: VirtAddr,
+    /// The next address to look at, without sign extension.
+    next: u64,
+    /// The run that is being built up.
+    pending: Option<Mapping>,
+}
+
+impl Mappings {
+    /// Walks the page table hierarchy whose level 4 table is in the given frame.
+    ///
+    /// # Safety
+    /// The frame must hold a valid level 4 page table, and the complete physical memory must be
+    /// mapped at [`physical_memory_offset`](crate::memory::physical_memory_offset).
+    pub unsafe fn new(level_4_frame: PhysFrame) -> Self {
+        Self {
+            level_4_frame,
+            physical_memory_offset: crate::memory::physical_memory_offset(),
+            next: 0,
+            pending: None,
+        }
+    }
+
+    fn table(&self, frame: PhysFrame) -> &'static PageTable {
+        let virt = self.physical_memory_offset + frame.start_address().as_u64();
+        unsafe { &*virt.as_ptr::<PageTable>() }
+    }
+
+    /// Finds the first mapped page at or after `self.next`, and advances past it.
+    fn next_page(&mut self) -> Option<Mapping> {
+        'search: while self.next < ADDRESS_SPACE_END {
+            let address = VirtAddr::new_truncate(self.next);
+            let indexes = [
+                address.p4_index(),
+                address.p3_index(),
+                address.p2_index(),
+                address.p1_index(),
+            ];
+
+            let mut frame = self.level_4_frame;
+            // Permissions granted by every level so far, and restrictions imposed by any level
+            let mut granted = RESTRICTIVE_FLAGS;
+            let mut no_execute = PageTableFlags::empty();
+            for (level, (&index, &entry_size)) in indexes.iter().zip(&ENTRY_SIZES).enumerate() {
+                let entry = &self.table(frame)[index];
+                let entry_flags = entry.flags() - VOLATILE_FLAGS;
+                if !entry_flags.contains(PageTableFlags::PRESENT) {
+                    self.next = align_down(self.next, entry_size) + entry_size;
+                    continue 'search;
+                }
+                granted &= entry_flags;
+                no_execute |= entry_flags & PageTableFlags::NO_EXECUTE;
+
+                let is_leaf =
+                    level == 3 || (level > 0 && entry_flags.contains(PageTableFlags::HUGE_PAGE));
+                if is_leaf {
+                    let start = align_down(self.next, entry_size);
+                    self.next = start + entry_size;
+                    let mut own_flags =
+                        entry_flags - RESTRICTIVE_FLAGS - PageTableFlags::NO_EXECUTE;
+                    // In a level 1 entry, the bit is the PAT index bit instead
+                    if level < 3 {
+                        own_flags -= PageTableFlags::HUGE_PAGE;
+                    }
+                    return Some(Mapping {
+                        start: VirtAddr::new_truncate(start),
+                        size: entry_size,
+                        physical_start: entry.addr(),
+                        page_size: entry_size,
+                        flags: own_flags | granted | no_execute,
+                    });
+                }
+                frame = PhysFrame::containing_address(entry.addr());
+            }
+        }
+        None
+    }
+}
+
+impl Iterator for Mappings {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        loop {
+            match (self.pending, self.next_page()) {
+                (None, None) => return None,
+                (None, Some(page)) => self.pending = Some(page),
+                (Some(run), None) => {
+                    self.pending = None;
+                    return Some(run);
+                }
+                (Some(run), Some(page)) if run.is_continued_by(&page) => {
+                    self.pending = Some(Mapping {
+                        size: run.size + page.size,
+                        ..run
+                    });
+                }
+                (Some(run), Some(page)) => {
+                    self.pending = Some(page);
+                    return Some(run);
+                }
+            }
+        }
+    }
+}
+
+/// Returns an iterator over the mappings of the active level 4 page table.
+///
+/// # Panics
+/// Panics if the memory subsystem hasn't been initialized yet.
+pub fn mappings() -> Mappings {
+    let (level_4_frame, _) = Cr3::read();
+    unsafe { Mappings::new(level_4_frame) }
+}
+
+/// Returns the page of the active page table that contains `address`, if it is mapped.
+///
+/// # Panics
+/// Panics if the memory subsystem hasn't been initialized yet.
+pub fn page_mapping(address: VirtAddr) -> Option<Mapping> {
+    let mut mappings = mappings();
+    mappings.next = address.as_u64() % ADDRESS_SPACE_END;
+    mappings
+        .next_page()
+        .filter(|mapping| mapping.contains(address))
+}
+
+/// Logs a table of the mappings of the active level 4 page table.
+pub fn log_mappings() {
+    info!(
+        "{:<18} {:<18} {:<14} {:>12} pg flags",
+        "virtual start", "virtual end", "physical", "bytes"
+    );
+    for mapping in mappings() {
+        info!("{}", mapping);
+    }
+}
+
+fn align_down(address: u64, alignment: u64) -> u64 {
+    address & !(alignment - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{HEAP_SIZE, HEAP_START};
+
+    #[test_case]
+    fn test_heap_is_mapped_writable() {
+        let heap_start = VirtAddr::new(HEAP_START as u64);
+        let heap_end = heap_start + HEAP_SIZE;
+        let mut covered = heap_start;
+        for mapping in
+            mappings().filter(|mapping| mapping.end() > heap_start && mapping.start < heap_end)
+        {
+            assert!(mapping.start <= covered);
+            assert!(mapping
+                .flags
+                .contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE));
+            assert!(!mapping.flags.contains(PageTableFlags::USER_ACCESSIBLE));
+            covered = mapping.end();
+        }
+        assert!(covered >= heap_end);
+    }
+
+    #[test_case]
+    fn test_page_mapping_finds_single_page() {
+        let heap_start = VirtAddr::new(HEAP_START as u64);
+        let page = page_mapping(heap_start + 10u64).unwrap();
+        assert_eq!(page.start, heap_start);
+        assert_eq!(page.size, 4096);
+        assert!(page_mapping(VirtAddr::new(0)).is_none());
+    }
+
+    #[test_case]
+    fn test_mapping_at_top_of_address_space() {
+        let mapping = Mapping {
+            start: VirtAddr::new(0xffff_ffff_ffe0_0000),
+            size: 2 << 20,
+            physical_start: PhysAddr::new(0),
+            page_size: 2 << 20,
+            flags: PageTableFlags::PRESENT,
+        };
+        assert_eq!(mapping.end(), VirtAddr::new(0));
+        assert!(mapping.contains(VirtAddr::new(0xffff_ffff_ffff_ffff)));
+        assert!(mapping.contains(mapping.start));
+        assert!(!mapping.contains(VirtAddr::new(0)));
+        assert!(!mapping.contains(VirtAddr::new(0xffff_ffff_ffdf_ffff)));
+    }
+
+    #[test_case]
+    fn test_mappings_are_sorted_and_merged() {
+        let mut previous: Option<Mapping> = None;
+        for mapping in mappings() {
+            if let Some(previous) = previous {
+                assert!(previous.end() <= mapping.start);
+                assert!(!previous.is_continued_by(&mapping));
+            }
+            previous = Some(mapping);
+        }
+        assert!(previous.is_some());
+    }
+}
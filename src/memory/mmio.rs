@@ -0,0 +1,226 @@
+use core::{
+    mem, ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::{
+    registers::model_specific::Msr,
+    structures::paging::{PageTableFlags, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    cpu,
+    memory::{layout::AreaKind, vmalloc, VirtualRegion, VmallocError, PAGE_SIZE},
+};
+
+/// The model specific register holding the page attribute table.
+const IA32_PAT: u32 = 0x277;
+/// The PAT entry that is reprogrammed to write-combining. It is selected by setting only
+/// `NO_CACHE`, which no other mapping does, and is uncacheable minus by default. Unlike the PAT
+/// bit, this works for pages of every size.
+const WRITE_COMBINING_PAT_INDEX: u64 = 2;
+const PAT_WRITE_COMBINING: u64 = 0x01;
+
+/// Whether write-combining is available, which is decided when the PAT is programmed.
+static WRITE_COMBINING: AtomicBool = AtomicBool::new(false);
+
+/// Programs the page attribute table for write-combining, if the CPU has one. This must happen
+/// during memory initialization, before any mapping selects the reprogrammed entry.
+pub(crate) fn initialize_page_attribute_table() {
+    let pat_supported = cpu::has_pat();
+    if pat_supported {
+        let mut pat = Msr::new(IA32_PAT);
+        unsafe {
+            let shift = WRITE_COMBINING_PAT_INDEX * 8;
+            let entries = pat.read() & !(0xff << shift);
+            pat.write(entries | (PAT_WRITE_COMBINING << shift));
+        }
+    }
+    WRITE_COMBINING.store(pat_supported, Ordering::Relaxed);
+}
+
+/// How the CPU may cache accesses to device memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Every access goes straight to the device, in order. This is right for device registers.
+    Uncacheable,
+    /// Reads may be cached, but every write goes to the device.
+    WriteThrough,
+    /// Writes may be buffered and combined, which suits frame buffers. Falls back to
+    /// `Uncacheable` if the CPU has no page attribute table.
+    WriteCombining,
+}
+
+impl CacheMode {
+    fn flags(self) -> PageTableFlags {
+        match self {
+            Self::Uncacheable => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+            Self::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            Self::WriteCombining if WRITE_COMBINING.load(Ordering::Relaxed) => {
+                PageTableFlags::NO_CACHE
+            }
+            Self::WriteCombining => Self::Uncacheable.flags(),
+        }
+    }
+}
+
+/// A range of device memory mapped into the MMIO area, which is unmapped when this is dropped.
+///
+/// All accesses go through volatile reads and writes, so the compiler never elides or merges
+/// them.
+#[derive(Debug)]
+pub struct MmioRegion {
+    region: VirtualRegion,
+    physical_start: PhysAddr,
+    /// The offset of `physical_start` in its page.
+    offset: usize,
+    len: usize,
+}
+
+impl MmioRegion {
+    /// The physical address the region starts at.
+    pub fn physical_start(&self) -> PhysAddr {
+        self.physical_start
+    }
+
+    /// The virtual address that `physical_start` is mapped at.
+    pub fn start(&self) -> VirtAddr {
+        self.region.start() + self.offset
+    }
+
+    /// The length of the region in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Reads a value of type `T` at the given byte offset.
+    ///
+    /// # Panics
+    /// Panics if the value doesn't lie within the region or isn't properly aligned.
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { ptr::read_volatile(self.pointer_to(offset)) }
+    }
+
+    /// Writes a value of type `T` at the given byte offset.
+    ///
+    /// # Panics
+    /// Panics if the value doesn't lie within the region or isn't properly aligned.
+    pub fn write<T: Copy>(&mut self, offset: usize, value: T) {
+        unsafe { ptr::write_volatile(self.pointer_to(offset), value) }
+    }
+
+    fn pointer_to<T>(&self, offset: usize) -> *mut T {
+        assert!(
+            offset
+                .checked_add(mem::size_of::<T>())
+                .is_some_and(|end| end <= self.len),
+            "MMIO access at offset {:#x} is out of bounds",
+            offset
+        );
+        let pointer: *mut T = (self.start() + offset).as_mut_ptr();
+        assert!(
+            pointer.is_aligned(),
+            "MMIO access at offset {:#x} is misaligned",
+            offset
+        );
+        pointer
+    }
+}
+
+/// Maps `len` bytes of device memory starting at `physical_start` as uncacheable.
+///
+/// # Safety
+/// The physical range must belong to a device, not to memory that is in use, and must not be
+/// mapped elsewhere with a different cache mode.
+pub unsafe fn map_mmio(physical_start: PhysAddr, len: usize) -> Result<MmioRegion, VmallocError> {
+    unsafe { map_mmio_with_cache_mode(physical_start, len, CacheMode::Uncacheable) }
+}
+
+/// Maps `len` bytes of device memory starting at `physical_start` with the given cache mode.
+///
+/// # Safety
+/// The physical range must belong to a device, not to memory that is in use, and must not be
+/// mapped elsewhere with a different cache mode.
+pub unsafe fn map_mmio_with_cache_mode(
+    physical_start: PhysAddr,
+    len: usize,
+    cache_mode: CacheMode,
+) -> Result<MmioRegion, VmallocError> {
+    let offset = physical_start.as_u64() as usize % PAGE_SIZE;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let region = unsafe {
+        vmalloc::map_physical(
+            AreaKind::Mmio,
+            PhysFrame::containing_address(physical_start),
+            offset + len,
+            flags | cache_mode.flags(),
+        )?
+    };
+    Ok(MmioRegion {
+        region,
+        physical_start,
+        offset,
+        len,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::page_mapping;
+
+    /// The VGA text buffer is device memory that is always present.
+    const VGA_BUFFER: u64 = 0xb8000;
+
+    #[test_case]
+    fn test_mmio_maps_device_memory() {
+        // Make sure the page tables for the area exist, since those frames are never freed
+        drop(unsafe { map_mmio(PhysAddr::new(VGA_BUFFER), 8) }.unwrap());
+        let free_frames = crate::memory::frame_allocator().lock().free_frames();
+
+        let region = unsafe { map_mmio(PhysAddr::new(VGA_BUFFER + 2), 8) }.unwrap();
+        assert_eq!(region.start().as_u64() % PAGE_SIZE as u64, 2);
+
+        let page = page_mapping(region.start()).unwrap();
+        assert_eq!(page.physical_start, PhysAddr::new(VGA_BUFFER));
+        assert!(page
+            .flags
+            .contains(PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH));
+
+        let direct: *const u16 =
+            (crate::memory::physical_memory_offset() + VGA_BUFFER + 2u64).as_ptr();
+        assert_eq!(region.read::<u16>(0), unsafe { direct.read_volatile() });
+
+        // The device memory must not be handed to the frame allocator
+        let start = region.start();
+        drop(region);
+        assert!(page_mapping(start).is_none());
+        assert_eq!(
+            crate::memory::frame_allocator().lock().free_frames(),
+            free_frames
+        );
+    }
+
+    #[test_case]
+    fn test_mmio_write_combining() {
+        let region = unsafe {
+            map_mmio_with_cache_mode(PhysAddr::new(VGA_BUFFER), 8, CacheMode::WriteCombining)
+        }
+        .unwrap();
+        let page = page_mapping(region.start()).unwrap();
+        assert!(page.flags.contains(PageTableFlags::NO_CACHE));
+        assert!(!page
+            .flags
+            .intersects(PageTableFlags::WRITE_THROUGH | PageTableFlags::HUGE_PAGE));
+
+        // The mapping has to be removed completely as well
+        let start = region.start();
+        drop(region);
+        assert!(page_mapping(start).is_none());
+    }
+}
@@ -0,0 +1,97 @@
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{Mapper, Page, PageTableFlags, Size1GiB, Size2MiB, Size4KiB},
+    VirtAddr,
+};
+
+use crate::memory::{mappings, Mapping};
+
+/// Enables the no-execute bit in page table entries. Setting `NO_EXECUTE` in an entry without
+/// this causes a page fault, so it must happen before any such mapping is created.
+pub(crate) fn enable_no_execute() {
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+}
+
+/// Makes sure no page of the active page table is both writable and executable, and turns on
+/// write protection for the kernel.
+///
+/// The bootloader maps the kernel's segments according to their ELF flags, so code is already
+/// read-only. Every other writable mapping, such as the boot stack and the physical memory
+/// mapping, holds data and is made non-executable. If the kernel's own code turns out to be
+/// writable, it is made read-only instead. Once `CR0.WP` is set, writes to read-only pages fault
+/// even in kernel mode.
+pub fn enforce_write_xor_execute() {
+    let kernel_code = VirtAddr::new(enforce_write_xor_execute as fn() as usize as u64);
+    for mapping in mappings() {
+        let executable = !mapping.flags.contains(PageTableFlags::NO_EXECUTE);
+        if !executable || !mapping.flags.contains(PageTableFlags::WRITABLE) {
+            continue;
+        }
+        let flags = if mapping.contains(kernel_code) {
+            mapping.flags - PageTableFlags::WRITABLE
+        } else {
+            mapping.flags | PageTableFlags::NO_EXECUTE
+        };
+        update_flags(&mapping, flags);
+    }
+
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+}
+
+/// Replaces the flags of every page in the run.
+fn update_flags(mapping: &Mapping, flags: PageTableFlags) {
+    let mut mapper = crate::memory::mapper().lock();
+    for offset in (0..mapping.size).step_by(mapping.page_size as usize) {
+        let address = mapping.start + offset;
+        let result = unsafe {
+            match mapping.page_size {
+                0x1000 => mapper
+                    .update_flags(Page::<Size4KiB>::containing_address(address), flags)
+                    .map(|flush| flush.flush()),
+                0x20_0000 => mapper
+                    .update_flags(Page::<Size2MiB>::containing_address(address), flags)
+                    .map(|flush| flush.flush()),
+                _ => mapper
+                    .update_flags(Page::<Size1GiB>::containing_address(address), flags)
+                    .map(|flush| flush.flush()),
+            }
+        };
+        result.expect("failed to update flags of a mapped page");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+
+    use super::*;
+
+    #[test_case]
+    fn test_no_mapping_is_writable_and_executable() {
+        for mapping in mappings() {
+            assert!(
+                mapping.flags.contains(PageTableFlags::NO_EXECUTE)
+                    || !mapping.flags.contains(PageTableFlags::WRITABLE),
+                "{} is writable and executable",
+                mapping
+            );
+        }
+    }
+
+    #[test_case]
+    fn test_heap_and_code_permissions() {
+        let heap_value = Box::new(0u64);
+        let heap_address = VirtAddr::from_ptr(&*heap_value);
+        let code_address = VirtAddr::new(test_heap_and_code_permissions as fn() as usize as u64);
+
+        let find = |address| mappings().find(|mapping: &Mapping| mapping.contains(address));
+        let heap = find(heap_address).unwrap();
+        assert!(heap.flags.contains(PageTableFlags::NO_EXECUTE));
+        let code = find(code_address).unwrap();
+        assert!(!code.flags.contains(PageTableFlags::WRITABLE));
+        assert!(!code.flags.contains(PageTableFlags::NO_EXECUTE));
+    }
+}
@@ -0,0 +1,317 @@
+use core::{
+    marker::PhantomData,
+    mem,
+    ptr::{self, NonNull},
+};
+
+use log::warn;
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+use crate::memory::{align_up, PAGE_SIZE};
+
+/// Bookkeeping stored at the start of every slab page.
+struct SlabHeader {
+    /// Neighbours in the cache's list of slabs that have free objects.
+    previous: Option<NonNull<SlabHeader>>,
+    next: Option<NonNull<SlabHeader>>,
+    free_list: Option<NonNull<FreeObject>>,
+    free_count: usize,
+}
+
+/// A free object slot, which links to the next free slot in the same slab.
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+/// A cache of objects of type `T`, carved out of whole pages.
+///
+/// Each slab is a single physical frame taken from the global frame allocator and accessed
+/// through the physical memory mapping, so the cache doesn't depend on the heap. A slab starts
+/// with a header that tracks its free objects, and slabs that become empty are returned to the
+/// frame allocator right away.
+///
+/// Dropping the cache returns its empty slabs as well. Slabs that still hold live objects are
+/// leaked, since the objects may still be in use, and a warning is logged.
+pub struct SlabCache<T> {
+    /// Slabs with at least one free object. Full slabs aren't tracked, since an object's slab
+    /// can be found by rounding its address down to the page.
+    partial: Option<NonNull<SlabHeader>>,
+    slabs: usize,
+    free_objects: usize,
+    _marker: PhantomData<T>,
+}
+
+// The cache owns its slabs, so it can be sent wherever the objects can be sent.
+unsafe impl<T: Send> Send for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    const SLOT_ALIGN: usize = max(mem::align_of::<T>(), mem::align_of::<FreeObject>());
+    const SLOT_SIZE: usize = align_up(
+        max(mem::size_of::<T>(), mem::size_of::<FreeObject>()),
+        Self::SLOT_ALIGN,
+    );
+    const FIRST_SLOT_OFFSET: usize = align_up(mem::size_of::<SlabHeader>(), Self::SLOT_ALIGN);
+
+    /// The number of objects that fit in a single slab.
+    pub const OBJECTS_PER_SLAB: usize =
+        PAGE_SIZE.saturating_sub(Self::FIRST_SLOT_OFFSET) / Self::SLOT_SIZE;
+
+    /// Creates an empty cache.
+    ///
+    /// # Panics
+    /// Panics if `T` is too large or too strictly aligned to fit in a page alongside the slab
+    /// header.
+    pub const fn new() -> Self {
+        assert!(
+            Self::OBJECTS_PER_SLAB > 0,
+            "type is too large for a slab cache"
+        );
+        Self {
+            partial: None,
+            slabs: 0,
+            free_objects: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    /// The number of slabs currently held by the cache.
+    pub fn slabs(&self) -> usize {
+        self.slabs
+    }
+
+    /// The number of unused object slots across all slabs.
+    pub fn free_objects(&self) -> usize {
+        self.free_objects
+    }
+
+    /// Moves `value` into a free slot and returns a pointer to it.
+    ///
+    /// Returns `None` if a new slab was needed but no frames are left.
+    pub fn allocate(&mut self, value: T) -> Option<NonNull<T>> {
+        let mut slab = match self.partial {
+            Some(slab) => slab,
+            None => self.add_slab()?,
+        };
+
+        let object = unsafe {
+            let header = slab.as_mut();
+            let object = header.free_list.unwrap();
+            header.free_list = object.as_ref().next;
+            header.free_count -= 1;
+            if header.free_count == 0 {
+                self.unlink(slab);
+            }
+            object.cast::<T>()
+        };
+        self.free_objects -= 1;
+
+        unsafe { object.as_ptr().write(value) };
+        Some(object)
+    }
+
+    /// Drops the object and returns its slot to the cache.
+    ///
+    /// # Safety
+    /// `object` must have been returned by `allocate` on this cache and not deallocated since.
+    pub unsafe fn deallocate(&mut self, object: NonNull<T>) {
+        let mut slab = NonNull::new(align_down(object.as_ptr() as usize) as *mut SlabHeader)
+            .expect("object isn't part of a slab");
+
+        let free_count = unsafe {
+            ptr::drop_in_place(object.as_ptr());
+            let free_object = object.cast::<FreeObject>();
+            let header = slab.as_mut();
+            free_object.as_ptr().write(FreeObject {
+                next: header.free_list,
+            });
+            header.free_list = Some(free_object);
+            header.free_count += 1;
+            header.free_count
+        };
+        self.free_objects += 1;
+
+        if free_count == 1 {
+            // The slab was full, so it isn't in the list yet
+            self.push(slab);
+        }
+        if free_count == Self::OBJECTS_PER_SLAB {
+            self.unlink(slab);
+            unsafe { self.release_slab(slab) };
+        }
+    }
+
+    /// Takes a frame from the frame allocator and formats it as an empty slab.
+    fn add_slab(&mut self) -> Option<NonNull<SlabHeader>> {
+        let frame: PhysFrame = crate::memory::frame_allocator().lock().allocate_frame()?;
+        let page = crate::memory::physical_memory_offset() + frame.start_address().as_u64();
+        let slab_ptr = page.as_mut_ptr::<SlabHeader>();
+
+        // Link every slot into the free list, starting from the lowest address
+        let mut free_list = None;
+        for index in (0..Self::OBJECTS_PER_SLAB).rev() {
+            let slot_addr = page + Self::FIRST_SLOT_OFFSET + index * Self::SLOT_SIZE;
+            let slot = slot_addr.as_mut_ptr::<FreeObject>();
+            unsafe { slot.write(FreeObject { next: free_list }) };
+            free_list = NonNull::new(slot);
+        }
+
+        unsafe {
+            slab_ptr.write(SlabHeader {
+                previous: None,
+                next: None,
+                free_list,
+                free_count: Self::OBJECTS_PER_SLAB,
+            });
+        }
+        let slab = NonNull::new(slab_ptr).unwrap();
+        self.push(slab);
+        self.slabs += 1;
+        self.free_objects += Self::OBJECTS_PER_SLAB;
+        Some(slab)
+    }
+
+    /// Gives the frame of an empty, unlinked slab back to the frame allocator.
+    unsafe fn release_slab(&mut self, slab: NonNull<SlabHeader>) {
+        let page = VirtAddr::from_ptr(slab.as_ptr());
+        let phys_addr = page - crate::memory::physical_memory_offset();
+        let frame: PhysFrame = PhysFrame::containing_address(PhysAddr::new(phys_addr));
+        unsafe {
+            crate::memory::frame_allocator()
+                .lock()
+                .deallocate_frame(frame)
+        };
+        self.slabs -= 1;
+        self.free_objects -= Self::OBJECTS_PER_SLAB;
+    }
+
+    /// Adds a slab to the front of the partial list.
+    fn push(&mut self, mut slab: NonNull<SlabHeader>) {
+        unsafe {
+            let header = slab.as_mut();
+            header.previous = None;
+            header.next = self.partial;
+            if let Some(mut next) = self.partial {
+                next.as_mut().previous = Some(slab);
+            }
+        }
+        self.partial = Some(slab);
+    }
+
+    /// Removes a slab from the partial list.
+    fn unlink(&mut self, mut slab: NonNull<SlabHeader>) {
+        unsafe {
+            let header = slab.as_mut();
+            match header.previous {
+                Some(mut previous) => previous.as_mut().next = header.next,
+                None => self.partial = header.next,
+            }
+            if let Some(mut next) = header.next {
+                next.as_mut().previous = header.previous;
+            }
+            header.previous = None;
+            header.next = None;
+        }
+    }
+}
+
+impl<T> Drop for SlabCache<T> {
+    fn drop(&mut self) {
+        let mut next = self.partial;
+        while let Some(slab) = next {
+            let free_count = unsafe {
+                next = slab.as_ref().next;
+                slab.as_ref().free_count
+            };
+            if free_count == Self::OBJECTS_PER_SLAB {
+                self.unlink(slab);
+                unsafe { self.release_slab(slab) };
+            }
+        }
+        if self.slabs > 0 {
+            warn!(
+                "slab cache dropped with {} live objects, leaking {} slabs",
+                self.slabs * Self::OBJECTS_PER_SLAB - self.free_objects,
+                self.slabs
+            );
+        }
+    }
+}
+
+impl<T> Default for SlabCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+fn align_down(addr: usize) -> usize {
+    addr & !(PAGE_SIZE - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[test_case]
+    fn test_slab_cache_returns_empty_slabs() {
+        let mut cache = SlabCache::<[u64; 32]>::new();
+        let count = SlabCache::<[u64; 32]>::OBJECTS_PER_SLAB * 2 + 1;
+        // Reserve up front, since growing the heap would take frames as well
+        let mut objects = Vec::with_capacity(count);
+        let free_frames = crate::memory::frame_allocator().lock().free_frames();
+
+        for i in 0..count {
+            objects.push(cache.allocate([i as u64; 32]).unwrap());
+        }
+        assert_eq!(cache.slabs(), 3);
+        for (i, object) in objects.iter().enumerate() {
+            assert_eq!(unsafe { object.as_ref() }[31], i as u64);
+        }
+
+        for object in objects {
+            unsafe { cache.deallocate(object) };
+        }
+        assert_eq!(cache.slabs(), 0);
+        assert_eq!(cache.free_objects(), 0);
+        assert_eq!(
+            crate::memory::frame_allocator().lock().free_frames(),
+            free_frames
+        );
+    }
+
+    #[test_case]
+    fn test_dropped_slab_cache_leaks_live_objects() {
+        let mut cache = SlabCache::<u64>::new();
+        let object = cache.allocate(7).unwrap();
+        let free_frames = crate::memory::frame_allocator().lock().free_frames();
+        drop(cache);
+        // The slab of the live object stays allocated
+        assert_eq!(unsafe { *object.as_ref() }, 7);
+        assert_eq!(
+            crate::memory::frame_allocator().lock().free_frames(),
+            free_frames
+        );
+
+        let slab: PhysFrame = PhysFrame::containing_address(PhysAddr::new(
+            align_down(object.as_ptr() as usize) as u64
+                - crate::memory::physical_memory_offset().as_u64(),
+        ));
+        unsafe {
+            crate::memory::frame_allocator()
+                .lock()
+                .deallocate_frame(slab)
+        };
+    }
+}
@@ -0,0 +1,64 @@
+use x86_64::VirtAddr;
+
+use crate::memory::{layout::AreaKind, vmalloc, VirtualRegion, VmallocError};
+
+/// The default size of a kernel stack.
+pub const KERNEL_STACK_SIZE: usize = 16 * 1024; // 16 KiB
+
+/// A kernel stack in the kernel stacks area, with an unmapped guard page below it.
+///
+/// Since stacks grow downwards, overflowing the stack hits the guard page and causes a page
+/// fault instead of silently overwriting whatever lies below. The stack is unmapped when this is
+/// dropped, so it must outlive any code running on it. Use [`core::mem::forget`] for stacks that
+/// are needed forever, like the interrupt stacks.
+#[derive(Debug)]
+pub struct KernelStack {
+    region: VirtualRegion,
+}
+
+impl KernelStack {
+    /// Maps a new stack of at least `size` bytes, rounded up to whole pages.
+    pub fn new(size: usize) -> Result<Self, VmallocError> {
+        // The pages are mapped up front, since a fault on the stack couldn't be handled
+        let region = vmalloc::allocate(AreaKind::KernelStacks, size, true)?;
+        Ok(Self { region })
+    }
+
+    /// The lowest usable address of the stack.
+    pub fn bottom(&self) -> VirtAddr {
+        self.region.start()
+    }
+
+    /// The address just past the end of the stack, which is the initial stack pointer.
+    pub fn top(&self) -> VirtAddr {
+        self.region.end()
+    }
+
+    /// The usable size of the stack in bytes.
+    pub fn size(&self) -> usize {
+        self.region.size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::structures::paging::mapper::Translate;
+
+    use super::*;
+
+    #[test_case]
+    fn test_kernel_stack_has_guard_page() {
+        let stack = KernelStack::new(KERNEL_STACK_SIZE).unwrap();
+        assert_eq!(stack.size(), KERNEL_STACK_SIZE);
+        assert_eq!(stack.top() - stack.bottom(), KERNEL_STACK_SIZE as u64);
+        assert_eq!(
+            crate::memory::area_containing(stack.bottom()).unwrap().kind,
+            AreaKind::KernelStacks
+        );
+
+        let mapper = crate::memory::mapper().lock();
+        assert!(mapper.translate_addr(stack.bottom()).is_some());
+        assert!(mapper.translate_addr(stack.top() - 1u64).is_some());
+        assert!(mapper.translate_addr(stack.bottom() - 1u64).is_none());
+    }
+}
@@ -0,0 +1,124 @@
+use core::ptr;
+
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+use crate::{
+    cpu,
+    memory::{page_mapping, COPY_ON_WRITE},
+};
+
+/// Why a copy between kernel and user memory was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserCopyError {
+    /// The address isn't mapped, or isn't accessible from user mode.
+    NotUserAccessible(VirtAddr),
+    /// The address is readable from user mode, but can't be written.
+    NotWritable(VirtAddr),
+}
+
+/// Copies `destination.len()` bytes from user memory at `source` into `destination`.
+///
+/// # Safety
+/// The user mappings of the source range must not change while copying.
+pub unsafe fn copy_from_user(
+    destination: &mut [u8],
+    source: *const u8,
+) -> Result<(), UserCopyError> {
+    check_user_range(VirtAddr::from_ptr(source), destination.len(), false)?;
+    cpu::with_user_access(|| unsafe {
+        ptr::copy_nonoverlapping(source, destination.as_mut_ptr(), destination.len())
+    });
+    Ok(())
+}
+
+/// Copies `source` to user memory at `destination`.
+///
+/// # Safety
+/// The user mappings of the destination range must not change while copying.
+pub unsafe fn copy_to_user(destination: *mut u8, source: &[u8]) -> Result<(), UserCopyError> {
+    check_user_range(VirtAddr::from_ptr(destination), source.len(), true)?;
+    cpu::with_user_access(|| unsafe {
+        ptr::copy_nonoverlapping(source.as_ptr(), destination, source.len())
+    });
+    Ok(())
+}
+
+/// Checks that every page of the range is mapped as user accessible, and writable or
+/// copy-on-write if requested.
+fn check_user_range(start: VirtAddr, len: usize, writable: bool) -> Result<(), UserCopyError> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = start
+        .as_u64()
+        .checked_add(len as u64)
+        .ok_or(UserCopyError::NotUserAccessible(start))?;
+
+    let mut address = start.as_u64();
+    while address < end {
+        let page = page_mapping(VirtAddr::new(address))
+            .filter(|page| page.flags.contains(PageTableFlags::USER_ACCESSIBLE))
+            .ok_or(UserCopyError::NotUserAccessible(VirtAddr::new(address)))?;
+        // Writes to copy-on-write pages are resolved by the page fault handler
+        if writable
+            && !page
+                .flags
+                .intersects(PageTableFlags::WRITABLE | COPY_ON_WRITE)
+        {
+            return Err(UserCopyError::NotWritable(VirtAddr::new(address)));
+        }
+        address = page.end().as_u64();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+
+    use x86_64::structures::paging::Page;
+
+    use super::*;
+    use crate::memory::{activate_kernel_address_space, AddressSpace, USER_START};
+
+    #[test_case]
+    fn test_kernel_memory_is_rejected() {
+        let kernel_value = Box::new([1u8; 8]);
+        let mut buffer = [0u8; 8];
+        let source = kernel_value.as_ptr();
+        assert_eq!(
+            unsafe { copy_from_user(&mut buffer, source) },
+            Err(UserCopyError::NotUserAccessible(VirtAddr::from_ptr(source)))
+        );
+    }
+
+    #[test_case]
+    fn test_copy_to_and_from_user_page() {
+        let free_frames = crate::memory::frame_allocator().lock().free_frames();
+        // Map the page in a separate address space, so that the kernel's tables stay untouched
+        let mut address_space = AddressSpace::new().unwrap();
+        let page = Page::containing_address(VirtAddr::new(USER_START as u64));
+        address_space
+            .map_user_page(page, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+            .unwrap();
+
+        // With SMAP enabled, this would fault without `stac`
+        let user_ptr = page.start_address().as_mut_ptr::<u8>();
+        let mut buffer = [0u8; 4];
+        let result = unsafe {
+            address_space.activate();
+            let result = copy_to_user(user_ptr, b"user")
+                .and_then(|()| copy_from_user(&mut buffer, user_ptr));
+            activate_kernel_address_space();
+            result
+        };
+        result.unwrap();
+        assert_eq!(&buffer, b"user");
+
+        drop(address_space);
+        assert_eq!(
+            crate::memory::frame_allocator().lock().free_frames(),
+            free_frames
+        );
+    }
+}
@@ -0,0 +1,571 @@
+use alloc::collections::BTreeMap;
+use core::fmt::Debug;
+
+use conquer_once::spin::Lazy;
+use x86_64::{
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags,
+        PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    cpu,
+    memory::{
+        align_up, demand_paging,
+        layout::{self, AreaKind},
+        BootInfoFrameAllocator, DATA_FLAGS, GIGANTIC_PAGE_SIZE, HUGE_PAGE_SIZE, PAGE_SIZE,
+    },
+    sync::Mutex,
+};
+
+/// The areas of the address space that regions are handed out from.
+const REGION_AREAS: [AreaKind; 3] = [AreaKind::Vmalloc, AreaKind::KernelStacks, AreaKind::Mmio];
+
+/// Free ranges of the areas in `REGION_AREAS`, as a map from start address to size in bytes.
+/// Neighbouring ranges in the same area are always merged.
+static FREE_RANGES: Lazy<Mutex<BTreeMap<usize, usize>>> = Lazy::new(|| {
+    let mut free_ranges = BTreeMap::new();
+    for kind in REGION_AREAS {
+        let area = layout::area(kind);
+        free_ranges.insert(area.start.as_u64() as usize, area.size as usize);
+    }
+    Mutex::new(free_ranges)
+});
+
+/// Whether the CPU supports 1 GiB pages, cached since `map_range` asks for every region.
+static GIGANTIC_PAGES: Lazy<bool> = Lazy::new(cpu::has_gigantic_pages);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmallocError {
+    /// No free range of the requested size is left in the area.
+    OutOfVirtualMemory,
+    /// There weren't enough physical frames to back the region.
+    OutOfFrames,
+    /// The maximum number of lazily-backed regions are already registered.
+    TooManyLazyRegions,
+}
+
+/// A page-granular range of kernel virtual memory handed out by [`vmalloc`], or from one of the
+/// other areas of the address space.
+///
+/// The region is unmapped, and the frames allocated for it are returned to the frame allocator,
+/// when it is dropped. Use [`core::mem::forget`] for regions that should live forever.
+#[derive(Debug)]
+pub struct VirtualRegion {
+    start: VirtAddr,
+    size: usize,
+    guard_size: usize,
+    lazy: bool,
+    /// Whether the mapped frames were allocated for the region, rather than belonging to a
+    /// device or someone else.
+    owns_frames: bool,
+}
+
+impl VirtualRegion {
+    /// The start of the usable part of the region.
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// The end of the usable part of the region, exclusive.
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    /// The size of the usable part of the region in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.start.as_mut_ptr()
+    }
+}
+
+impl Drop for VirtualRegion {
+    fn drop(&mut self) {
+        if self.lazy {
+            demand_paging::unregister_lazy_region(self.start);
+        }
+        {
+            let mut mapper = crate::memory::mapper().lock();
+            if self.owns_frames {
+                let mut frame_allocator = crate::memory::frame_allocator().lock();
+                unmap_range(
+                    &mut mapper,
+                    Some(&mut frame_allocator),
+                    self.start,
+                    self.size,
+                );
+            } else {
+                unmap_range(&mut mapper, None, self.start, self.size);
+            }
+        }
+        release(
+            self.start.as_u64() as usize - self.guard_size,
+            self.size + 2 * self.guard_size,
+        );
+    }
+}
+
+/// Allocates a region of kernel virtual memory of at least `size` bytes and backs it with
+/// frames from the frame allocator.
+///
+/// If `guard` is set, the region is surrounded by an unmapped page on each side, so that
+/// running off either end causes a page fault instead of touching other memory.
+pub fn vmalloc(size: usize, guard: bool) -> Result<VirtualRegion, VmallocError> {
+    allocate(AreaKind::Vmalloc, size, guard)
+}
+
+/// Like [`vmalloc`], but takes the region from the given area.
+///
+/// # Panics
+/// Panics if regions aren't handed out from the area.
+pub(crate) fn allocate(
+    kind: AreaKind,
+    size: usize,
+    guard: bool,
+) -> Result<VirtualRegion, VmallocError> {
+    let region = reserve_region(kind, size, guard, 0)?;
+
+    let result = {
+        let mut mapper = crate::memory::mapper().lock();
+        let mut frame_allocator = crate::memory::frame_allocator().lock();
+        map_range(
+            &mut mapper,
+            &mut frame_allocator,
+            region.start,
+            region.size,
+            None,
+            DATA_FLAGS,
+        )
+    };
+    // On failure, dropping the region unmaps whatever was mapped and releases the range
+    result.map(|()| region)
+}
+
+/// Reserves a region of kernel virtual memory of at least `size` bytes without backing it.
+/// Instead, each page is mapped to a zeroed frame by the page fault handler when it is first
+/// accessed, so memory is only committed for the pages that are actually used.
+///
+/// If `guard` is set, the region is surrounded by an unmapped page on each side.
+pub fn vmalloc_lazy(size: usize, guard: bool) -> Result<VirtualRegion, VmallocError> {
+    let mut region = reserve_region(AreaKind::Vmalloc, size, guard, 0)?;
+    if !demand_paging::register_lazy_region(region.start(), region.end()) {
+        return Err(VmallocError::TooManyLazyRegions);
+    }
+    region.lazy = true;
+    Ok(region)
+}
+
+/// Maps the physical range of `size` bytes starting at the frame `physical_start` into the given
+/// area, surrounded by guard pages. The frames aren't freed when the region is dropped.
+///
+/// # Safety
+/// Mapping the range with the given flags must not break memory safety, e.g. by aliasing memory
+/// that is in use with conflicting cache attributes.
+pub(crate) unsafe fn map_physical(
+    kind: AreaKind,
+    physical_start: PhysFrame,
+    size: usize,
+    flags: PageTableFlags,
+) -> Result<VirtualRegion, VmallocError> {
+    let physical_start = physical_start.start_address();
+    let mut region = reserve_region(kind, size, true, physical_start.as_u64() as usize)?;
+    region.owns_frames = false;
+
+    let result = {
+        let mut mapper = crate::memory::mapper().lock();
+        let mut frame_allocator = crate::memory::frame_allocator().lock();
+        map_range(
+            &mut mapper,
+            &mut frame_allocator,
+            region.start,
+            region.size,
+            Some(physical_start),
+            flags,
+        )
+    };
+    // On failure, dropping the region unmaps whatever was mapped and releases the range
+    result.map(|()| region)
+}
+
+/// Creates a region in the given area with page-aligned size, with the given guard pages,
+/// without mapping it.
+///
+/// Regions large enough for huge or gigantic pages are placed at the same offset from a
+/// boundary of that page size as `physical_start`, so that the large pages can be used where the
+/// physical memory is aligned the same way. Pass zero for regions backed by allocated frames.
+fn reserve_region(
+    kind: AreaKind,
+    size: usize,
+    guard: bool,
+    physical_start: usize,
+) -> Result<VirtualRegion, VmallocError> {
+    let size = align_up(size.max(1), PAGE_SIZE);
+    let guard_size = if guard { PAGE_SIZE } else { 0 };
+    let alignment = if size >= GIGANTIC_PAGE_SIZE && *GIGANTIC_PAGES {
+        GIGANTIC_PAGE_SIZE
+    } else if size >= HUGE_PAGE_SIZE {
+        HUGE_PAGE_SIZE
+    } else {
+        PAGE_SIZE
+    };
+    // The reserved range starts with the guard page, so shift the alignment back by that
+    let skew = (guard_size + alignment - physical_start % alignment) % alignment;
+    let start = reserve(kind, size + 2 * guard_size, alignment, skew)? + guard_size;
+    Ok(VirtualRegion {
+        start: VirtAddr::new(start as u64),
+        size,
+        guard_size,
+        lazy: false,
+        owns_frames: true,
+    })
+}
+
+/// Takes a range of the given size out of the free ranges of an area, using the first one that
+/// fits. The start of the range plus `skew` is a multiple of `alignment`.
+fn reserve(
+    kind: AreaKind,
+    size: usize,
+    alignment: usize,
+    skew: usize,
+) -> Result<usize, VmallocError> {
+    assert!(
+        REGION_AREAS.contains(&kind),
+        "regions aren't handed out from the {} area",
+        kind.name()
+    );
+    let area = layout::area(kind);
+    let area_range = area.start.as_u64() as usize..area.end().as_u64() as usize;
+
+    let mut free_ranges = FREE_RANGES.lock();
+    let (range_start, range_size, start) = free_ranges
+        .range(area_range)
+        .map(|(&range_start, &range_size)| {
+            let start = align_up(range_start + skew, alignment) - skew;
+            (range_start, range_size, start)
+        })
+        .find(|&(range_start, range_size, start)| start + size <= range_start + range_size)
+        .ok_or(VmallocError::OutOfVirtualMemory)?;
+
+    // Whatever is left before and after the region stays free
+    free_ranges.remove(&range_start);
+    if start > range_start {
+        free_ranges.insert(range_start, start - range_start);
+    }
+    if range_start + range_size > start + size {
+        free_ranges.insert(start + size, range_start + range_size - (start + size));
+    }
+    Ok(start)
+}
+
+/// Returns a range to the free ranges, merging it with its neighbours in the same area.
+fn release(mut start: usize, mut size: usize) {
+    let area = layout::area_containing(VirtAddr::new(start as u64))
+        .expect("released range is outside of the layout");
+    let area_start = area.start.as_u64() as usize;
+    let area_end = area.end().as_u64() as usize;
+
+    let mut free_ranges = FREE_RANGES.lock();
+    if start + size < area_end {
+        if let Some(next_size) = free_ranges.remove(&(start + size)) {
+            size += next_size;
+        }
+    }
+    if let Some((&previous_start, &previous_size)) =
+        free_ranges.range(area_start..start).next_back()
+    {
+        if previous_start + previous_size == start {
+            start = previous_start;
+            size += previous_size;
+        }
+    }
+    free_ranges.insert(start, size);
+}
+
+/// Maps `size` bytes at `start`, either to newly allocated frames or to the physical range
+/// starting at `physical_start`. The largest pages the CPU supports are used wherever the
+/// virtual and physical addresses are both aligned for them, unless the flags select a PAT
+/// entry, which works differently for large pages. Stops at the first failure, leaving the pages
+/// mapped so far in place.
+fn map_range(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    start: VirtAddr,
+    size: usize,
+    physical_start: Option<PhysAddr>,
+    flags: PageTableFlags,
+) -> Result<(), VmallocError> {
+    let mut offset = 0;
+    while offset < size {
+        let address = start + offset;
+        let fits_page = |page_size: usize| {
+            !flags.contains(PageTableFlags::HUGE_PAGE)
+                && address.is_aligned(page_size as u64)
+                && size - offset >= page_size
+                && physical_start
+                    .is_none_or(|physical| (physical + offset).is_aligned(page_size as u64))
+        };
+        let physical = physical_start.map(|physical| physical + offset);
+        if *GIGANTIC_PAGES
+            && fits_page(GIGANTIC_PAGE_SIZE)
+            && map_huge_page::<Size1GiB>(mapper, frame_allocator, address, physical, flags)?
+        {
+            offset += GIGANTIC_PAGE_SIZE;
+            continue;
+        }
+        if fits_page(HUGE_PAGE_SIZE)
+            && map_huge_page::<Size2MiB>(mapper, frame_allocator, address, physical, flags)?
+        {
+            offset += HUGE_PAGE_SIZE;
+            continue;
+        }
+
+        let page = Page::<Size4KiB>::containing_address(address);
+        let frame = match physical_start {
+            Some(physical) => PhysFrame::containing_address(physical + offset),
+            None => frame_allocator
+                .allocate_frame()
+                .ok_or(VmallocError::OutOfFrames)?,
+        };
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(MapToError::FrameAllocationFailed) => {
+                if physical_start.is_none() {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+                return Err(VmallocError::OutOfFrames);
+            }
+            Err(error) => panic!("failed to map {:?} to {:?}: {:?}", page, frame, error),
+        }
+        offset += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+/// Tries to map a single huge or gigantic page at `address`. Returns `false` if the caller
+/// should fall back to smaller pages, because no aligned frames are free or a page table for
+/// smaller pages is already in place.
+pub(super) fn map_huge_page<S: PageSize + Debug>(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    address: VirtAddr,
+    physical_start: Option<PhysAddr>,
+    flags: PageTableFlags,
+) -> Result<bool, VmallocError>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+    BootInfoFrameAllocator: FrameAllocator<S> + FrameDeallocator<S>,
+{
+    let page = Page::<S>::containing_address(address);
+    let frame: PhysFrame<S> = match physical_start {
+        Some(physical) => PhysFrame::containing_address(physical),
+        None => match frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return Ok(false),
+        },
+    };
+    let release_frame = |frame_allocator: &mut BootInfoFrameAllocator| {
+        if physical_start.is_none() {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
+    };
+
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(true)
+        }
+        Err(MapToError::PageAlreadyMapped(_)) => {
+            release_frame(frame_allocator);
+            Ok(false)
+        }
+        Err(MapToError::FrameAllocationFailed) => {
+            release_frame(frame_allocator);
+            Err(VmallocError::OutOfFrames)
+        }
+        Err(error) => panic!("failed to map {:?} to {:?}: {:?}", page, frame, error),
+    }
+}
+
+/// Unmaps `size` bytes at `start`, and frees the frames if a frame allocator is given. Pages
+/// that aren't mapped are skipped.
+fn unmap_range(
+    mapper: &mut OffsetPageTable<'static>,
+    mut frame_allocator: Option<&mut BootInfoFrameAllocator>,
+    start: VirtAddr,
+    size: usize,
+) {
+    let mut offset = 0;
+    while offset < size {
+        let address = start + offset;
+        match mapper.translate(address) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size1GiB(_),
+                ..
+            } => {
+                let page = Page::<Size1GiB>::containing_address(address);
+                let (frame, flush) = mapper.unmap(page).expect("failed to unmap gigantic page");
+                flush.flush();
+                if let Some(frame_allocator) = frame_allocator.as_mut() {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+                offset += GIGANTIC_PAGE_SIZE;
+            }
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(_),
+                ..
+            } => {
+                let page = Page::<Size2MiB>::containing_address(address);
+                let (frame, flush) = mapper.unmap(page).expect("failed to unmap huge page");
+                flush.flush();
+                if let Some(frame_allocator) = frame_allocator.as_mut() {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+                offset += HUGE_PAGE_SIZE;
+            }
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(_),
+                ..
+            } => {
+                let page = Page::<Size4KiB>::containing_address(address);
+                let (frame, flush) = mapper.unmap(page).expect("failed to unmap page");
+                flush.flush();
+                if let Some(frame_allocator) = frame_allocator.as_mut() {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+                offset += PAGE_SIZE;
+            }
+            TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => {
+                offset += PAGE_SIZE
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::structures::paging::mapper::Translate;
+
+    use super::*;
+
+    #[test_case]
+    fn test_vmalloc_maps_and_frees_region() {
+        // Make sure the page tables for the area exist, since those frames are never freed
+        drop(vmalloc(PAGE_SIZE, true).unwrap());
+        let free_frames = crate::memory::frame_allocator().lock().free_frames();
+
+        let region = vmalloc(3 * PAGE_SIZE, true).unwrap();
+        assert_eq!(region.size(), 3 * PAGE_SIZE);
+        assert_eq!(
+            crate::memory::frame_allocator().lock().free_frames(),
+            free_frames - 3
+        );
+        unsafe { region.as_mut_ptr::<u8>().write_bytes(0xab, region.size()) };
+
+        {
+            let mapper = crate::memory::mapper().lock();
+            assert!(mapper.translate_addr(region.start() - 1u64).is_none());
+            assert!(mapper.translate_addr(region.end()).is_none());
+        }
+
+        let start = region.start();
+        drop(region);
+        assert_eq!(
+            crate::memory::frame_allocator().lock().free_frames(),
+            free_frames
+        );
+        // The released range is handed out again
+        assert_eq!(vmalloc(PAGE_SIZE, true).unwrap().start(), start);
+    }
+
+    #[test_case]
+    fn test_lazy_region_is_backed_on_access() {
+        // Make sure the page tables for the area exist, since those frames are never freed
+        drop(vmalloc(PAGE_SIZE, true).unwrap());
+        let free_frames = crate::memory::frame_allocator().lock().free_frames();
+
+        let region = vmalloc_lazy(4 * PAGE_SIZE, true).unwrap();
+        assert_eq!(
+            crate::memory::frame_allocator().lock().free_frames(),
+            free_frames
+        );
+
+        let page = unsafe { region.as_mut_ptr::<u64>().add(PAGE_SIZE / 8) };
+        unsafe {
+            assert_eq!(page.add(1).read_volatile(), 0);
+            page.write_volatile(42);
+            assert_eq!(page.read_volatile(), 42);
+        }
+        assert_eq!(
+            crate::memory::frame_allocator().lock().free_frames(),
+            free_frames - 1
+        );
+
+        drop(region);
+        assert_eq!(
+            crate::memory::frame_allocator().lock().free_frames(),
+            free_frames
+        );
+    }
+
+    #[test_case]
+    fn test_gigantic_physical_range_uses_gigantic_pages() {
+        if !*GIGANTIC_PAGES {
+            return;
+        }
+        // Nothing is at 1 GiB in QEMU's default machine, and the range is never accessed
+        let physical_start = PhysFrame::containing_address(PhysAddr::new(1 << 30));
+        let map = || unsafe {
+            map_physical(
+                AreaKind::Mmio,
+                physical_start,
+                GIGANTIC_PAGE_SIZE + PAGE_SIZE,
+                PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+            )
+            .unwrap()
+        };
+        // Make sure the page tables for the tail page exist, since those frames are never freed
+        let warm_up_start = map().start();
+        let free_frames = crate::memory::frame_allocator().lock().free_frames();
+
+        let region = map();
+        assert_eq!(region.start(), warm_up_start);
+        let page = crate::memory::page_mapping(region.start()).unwrap();
+        assert_eq!(page.page_size, GIGANTIC_PAGE_SIZE as u64);
+        assert_eq!(page.physical_start, physical_start.start_address());
+        let tail = crate::memory::page_mapping(region.start() + GIGANTIC_PAGE_SIZE).unwrap();
+        assert_eq!(tail.page_size, PAGE_SIZE as u64);
+
+        let start = region.start();
+        drop(region);
+        assert!(crate::memory::page_mapping(start).is_none());
+        assert_eq!(
+            crate::memory::frame_allocator().lock().free_frames(),
+            free_frames
+        );
+    }
+
+    #[test_case]
+    fn test_large_region_uses_huge_pages() {
+        // Make sure the page tables for the area exist, since those frames are never freed
+        drop(vmalloc(2 * HUGE_PAGE_SIZE, false).unwrap());
+        let free_frames = crate::memory::frame_allocator().lock().free_frames();
+
+        let region = vmalloc(2 * HUGE_PAGE_SIZE, false).unwrap();
+        assert!(region.start().is_aligned(HUGE_PAGE_SIZE as u64));
+        let page = crate::memory::page_mapping(region.start()).unwrap();
+        assert_eq!(page.page_size, HUGE_PAGE_SIZE as u64);
+        unsafe { region.as_mut_ptr::<u8>().write_bytes(0xab, region.size()) };
+
+        drop(region);
+        assert_eq!(
+            crate::memory::frame_allocator().lock().free_frames(),
+            free_frames
+        );
+    }
+}
@@ -0,0 +1,268 @@
+//! Access to the PCI configuration space and the base address registers of devices.
+
+use tinypci::PciDeviceInfo;
+use x86_64::{instructions::port::Port, PhysAddr};
+
+use crate::{
+    memory::{map_mmio_with_cache_mode, CacheMode, MmioRegion, VmallocError},
+    sync::Mutex,
+};
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+/// The offset of the command register, whose low half is the command and high half the status.
+const COMMAND_OFFSET: u8 = 0x04;
+/// The I/O space and memory space enable bits of the command register.
+const COMMAND_DECODE_ENABLE: u32 = 0x3;
+
+/// The offset of the first base address register in the configuration space header.
+const BAR_OFFSET: u8 = 0x10;
+pub const BAR_COUNT: usize = 6;
+
+/// The offset of the register whose low byte is the legacy IRQ line the firmware assigned.
+const INTERRUPT_LINE_OFFSET: u8 = 0x3c;
+
+/// The address and data ports have to be used as a pair.
+static CONFIG_PORTS: Mutex<(Port<u32>, Port<u32>)> =
+    Mutex::new((Port::new(CONFIG_ADDRESS), Port::new(CONFIG_DATA)));
+
+/// The address of a single function of a PCI device. Multi-function devices have a separate
+/// configuration space, with its own BARs and interrupt line, for each function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciFunction {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciFunction {
+    /// Returns the functions a scanned device implements, which always include function 0.
+    pub fn all(device_info: &PciDeviceInfo) -> impl Iterator<Item = PciFunction> {
+        let (bus, device) = (device_info.bus, device_info.device);
+        let supported_fns = device_info.supported_fns;
+        (0..8u8)
+            .filter(move |&function| function == 0 || supported_fns[usize::from(function)])
+            .map(move |function| PciFunction {
+                bus,
+                device,
+                function,
+            })
+    }
+}
+
+impl From<&PciDeviceInfo> for PciFunction {
+    /// The first function of a scanned device.
+    fn from(device_info: &PciDeviceInfo) -> Self {
+        PciFunction {
+            bus: device_info.bus,
+            device: device_info.device,
+            function: 0,
+        }
+    }
+}
+
+/// Reads a register from the configuration space of a function.
+pub fn read_config(function: PciFunction, offset: u8) -> u32 {
+    let mut ports = CONFIG_PORTS.lock();
+    unsafe {
+        ports.0.write(config_address(function, offset));
+        ports.1.read()
+    }
+}
+
+/// Writes a register in the configuration space of a function.
+///
+/// # Safety
+/// Writing configuration registers changes how the device behaves, which can break memory
+/// safety, e.g. by moving a BAR over memory that is in use.
+pub unsafe fn write_config(function: PciFunction, offset: u8, value: u32) {
+    let mut ports = CONFIG_PORTS.lock();
+    unsafe {
+        ports.0.write(config_address(function, offset));
+        ports.1.write(value);
+    }
+}
+
+fn config_address(function: PciFunction, offset: u8) -> u32 {
+    0x8000_0000
+        | (function.bus as u32) << 16
+        | (function.device as u32) << 11
+        | (function.function as u32 & 0x7) << 8
+        | (offset & 0xfc) as u32
+}
+
+/// Returns the legacy IRQ line the function's INTx pin is connected to, which can be passed to
+/// [`register_irq`](crate::interrupt::register_irq), or `None` if it has none.
+pub fn interrupt_line(function: PciFunction) -> Option<u8> {
+    let line = read_config(function, INTERRUPT_LINE_OFFSET) as u8;
+    // The firmware leaves 0xff for devices without an interrupt pin or an assigned line
+    (line < crate::interrupt::IRQ_LINES).then_some(line)
+}
+
+/// A decoded base address register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: PhysAddr,
+        size: u64,
+        /// Whether reads have no side effects, so the range may be mapped write-combining.
+        prefetchable: bool,
+    },
+    Io {
+        port: u16,
+        size: u32,
+    },
+}
+
+impl Bar {
+    /// Maps a memory BAR, write-combining if it is prefetchable and uncacheable otherwise.
+    ///
+    /// Returns `None` for I/O BARs.
+    ///
+    /// # Safety
+    /// The BAR must not be mapped elsewhere with a different cache mode.
+    pub unsafe fn map(&self) -> Option<Result<MmioRegion, VmallocError>> {
+        match *self {
+            Bar::Memory {
+                address,
+                size,
+                prefetchable,
+            } => {
+                let cache_mode = if prefetchable {
+                    CacheMode::WriteCombining
+                } else {
+                    CacheMode::Uncacheable
+                };
+                Some(unsafe { map_mmio_with_cache_mode(address, size as usize, cache_mode) })
+            }
+            Bar::Io { .. } => None,
+        }
+    }
+}
+
+/// Decodes the BAR with the given index, or returns `None` if the device doesn't implement it.
+///
+/// The size is found by writing all ones to the register and reading back which bits stuck, so
+/// this must not race with a driver using the device.
+pub fn bar(function: PciFunction, index: usize) -> Option<Bar> {
+    assert!(index < BAR_COUNT, "BAR index {} is out of range", index);
+    let offset = BAR_OFFSET + 4 * index as u8;
+    let value = read_config(function, offset);
+    let size_mask = unsafe { probe(function, offset, value) };
+
+    if value & 1 == 1 {
+        let size_mask = size_mask & !0x3;
+        if size_mask == 0 {
+            return None;
+        }
+        return Some(Bar::Io {
+            port: (value & !0x3) as u16,
+            size: (!size_mask).wrapping_add(1) & 0xffff,
+        });
+    }
+
+    let is_64_bit = (value >> 1) & 0x3 == 0x2;
+    let (address, size_mask) = if is_64_bit {
+        if index + 1 >= BAR_COUNT {
+            return None;
+        }
+        let high_offset = offset + 4;
+        let high_value = read_config(function, high_offset);
+        let high_size_mask = unsafe { probe(function, high_offset, high_value) };
+        (
+            (high_value as u64) << 32 | (value & !0xf) as u64,
+            (high_size_mask as u64) << 32 | (size_mask & !0xf) as u64,
+        )
+    } else {
+        if size_mask & !0xf == 0 {
+            return None;
+        }
+        // A 32-bit BAR can't be moved above 4 GiB, as if the upper bits didn't stick
+        (
+            (value & !0xf) as u64,
+            0xffff_ffff_0000_0000 | (size_mask & !0xf) as u64,
+        )
+    };
+    if size_mask == 0 {
+        return None;
+    }
+
+    Some(Bar::Memory {
+        address: PhysAddr::new(address),
+        size: (!size_mask).wrapping_add(1),
+        prefetchable: value & 0x8 != 0,
+    })
+}
+
+/// Returns every implemented BAR of a device along with its index. The upper half of a 64-bit
+/// BAR is skipped.
+pub fn bars(function: PciFunction) -> impl Iterator<Item = (usize, Bar)> {
+    let mut index = 0;
+    core::iter::from_fn(move || {
+        while index < BAR_COUNT {
+            let current = index;
+            let value = read_config(function, BAR_OFFSET + 4 * current as u8);
+            let is_64_bit_memory = value & 1 == 0 && (value >> 1) & 0x3 == 0x2;
+            index += if is_64_bit_memory { 2 } else { 1 };
+            if let Some(bar) = bar(function, current) {
+                return Some((current, bar));
+            }
+        }
+        None
+    })
+}
+
+/// Writes all ones to a BAR and returns which bits stuck, then restores the original value.
+/// Decoding is turned off meanwhile, so the device doesn't claim accesses at the bogus address.
+unsafe fn probe(function: PciFunction, offset: u8, value: u32) -> u32 {
+    let command = read_config(function, COMMAND_OFFSET);
+    unsafe {
+        write_config(function, COMMAND_OFFSET, command & !COMMAND_DECODE_ENABLE);
+        write_config(function, offset, 0xffff_ffff);
+        let size_mask = read_config(function, offset);
+        write_config(function, offset, value);
+        write_config(function, COMMAND_OFFSET, command);
+        size_mask
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_config_address_selects_function() {
+        let function = PciFunction {
+            bus: 1,
+            device: 2,
+            function: 3,
+        };
+        assert_eq!(config_address(function, 0x3d), 0x8001_133c);
+    }
+
+    #[test_case]
+    fn test_map_vga_frame_buffer_bar() {
+        // QEMU's standard VGA device has its frame buffer in a prefetchable BAR 0
+        let vga = tinypci::brute_force_scan()
+            .into_iter()
+            .find(|device_info| device_info.vendor_id == 0x1234 && device_info.device_id == 0x1111)
+            .expect("QEMU VGA device not found");
+
+        let bar = bar(PciFunction::from(&vga), 0).unwrap();
+        let size = match bar {
+            Bar::Memory {
+                size, prefetchable, ..
+            } => {
+                assert!(prefetchable);
+                size
+            }
+            Bar::Io { .. } => panic!("VGA frame buffer is an I/O BAR"),
+        };
+
+        let mut frame_buffer = unsafe { bar.map() }.unwrap().unwrap();
+        assert_eq!(frame_buffer.len() as u64, size);
+        frame_buffer.write::<u32>(0, 0x00ff_00ff);
+        assert_eq!(frame_buffer.read::<u32>(0), 0x00ff_00ff);
+    }
+}
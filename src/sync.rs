@@ -11,4 +11,10 @@ impl<T> Mutex<T> {
     pub fn lock(&self) -> SpinlockGuard<'_, T> {
         self.0.lock()
     }
+
+    /// Attempts to acquire the lock without spinning. This is useful in interrupt handlers,
+    /// which would deadlock if the interrupted code holds the lock.
+    pub fn try_lock(&self) -> Option<SpinlockGuard<'_, T>> {
+        self.0.try_lock()
+    }
 }
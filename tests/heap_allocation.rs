@@ -6,7 +6,7 @@
 
 extern crate alloc;
 
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, vec, vec::Vec};
 use core::panic::PanicInfo;
 
 use bootloader::{entry_point, BootInfo};
@@ -59,3 +59,107 @@ fn large_vec() {
     }
     assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
 }
+
+#[test_case]
+fn allocation_larger_than_initial_heap() {
+    let size = os::memory::HEAP_SIZE * 4;
+    let buffer = vec![1u8; size];
+    assert_eq!(
+        buffer.iter().map(|&byte| byte as usize).sum::<usize>(),
+        size
+    );
+}
+
+#[cfg(all(feature = "alloc-fixed-block", not(feature = "heap-debug")))]
+#[test_case]
+fn statistics_track_allocations() {
+    let before = os::memory::heap_statistics();
+
+    let value = Box::new(7u64);
+    let during = os::memory::heap_statistics();
+    assert_eq!(during.bytes_in_use, before.bytes_in_use + 8);
+    assert_eq!(
+        during.block_sizes[0].allocations,
+        before.block_sizes[0].allocations + 1
+    );
+    assert!(during.peak_bytes_in_use >= during.bytes_in_use);
+
+    drop(value);
+    let after = os::memory::heap_statistics();
+    assert_eq!(after.bytes_in_use, before.bytes_in_use);
+    assert_eq!(after.block_sizes[0].frees, before.block_sizes[0].frees + 1);
+    assert!(after.block_sizes[0].free_list_length >= 1);
+}
+
+#[cfg(all(feature = "alloc-fixed-block", not(feature = "heap-debug")))]
+#[test_case]
+fn reclaim_cached_blocks() {
+    let boxes: Vec<Box<[u8; 64]>> = (0..100).map(|_| Box::new([0; 64])).collect();
+    drop(boxes);
+    let before = os::memory::heap_statistics();
+    assert!(before.block_sizes[3].free_list_length >= 100);
+
+    let reclaimed = os::memory::reclaim_heap();
+    let after = os::memory::heap_statistics();
+    assert!(reclaimed >= 100 * 64);
+    assert!(after
+        .block_sizes
+        .iter()
+        .all(|block_size| block_size.free_list_length == 0));
+    assert!(after.fallback_free_bytes >= before.fallback_free_bytes + reclaimed);
+}
+
+// With heap-debug, each allocation carries a 16 byte redzone on either side, which the
+// statistics count as part of the allocation
+#[cfg(all(feature = "alloc-fixed-block", feature = "heap-debug"))]
+#[test_case]
+fn statistics_track_guarded_allocations() {
+    let before = os::memory::heap_statistics();
+
+    // 16 + 8 + 16 bytes fit in a 64 byte block
+    let value = Box::new(7u64);
+    let during = os::memory::heap_statistics();
+    assert_eq!(during.bytes_in_use, before.bytes_in_use + 40);
+    assert_eq!(
+        during.block_sizes[3].allocations,
+        before.block_sizes[3].allocations + 1
+    );
+    assert!(during.peak_bytes_in_use >= during.bytes_in_use);
+
+    drop(value);
+    let after = os::memory::heap_statistics();
+    assert_eq!(after.bytes_in_use, before.bytes_in_use);
+    assert_eq!(after.block_sizes[3].frees, before.block_sizes[3].frees + 1);
+    assert!(after.block_sizes[3].free_list_length >= 1);
+}
+
+#[cfg(all(feature = "alloc-fixed-block", feature = "heap-debug"))]
+#[test_case]
+fn reclaim_cached_guarded_blocks() {
+    // 16 + 64 + 16 bytes fit in a 128 byte block
+    let boxes: Vec<Box<[u8; 64]>> = (0..100).map(|_| Box::new([0; 64])).collect();
+    drop(boxes);
+    let before = os::memory::heap_statistics();
+    assert!(before.block_sizes[4].free_list_length >= 100);
+
+    let reclaimed = os::memory::reclaim_heap();
+    let after = os::memory::heap_statistics();
+    assert!(reclaimed >= 100 * 128);
+    assert!(after
+        .block_sizes
+        .iter()
+        .all(|block_size| block_size.free_list_length == 0));
+    assert!(after.fallback_free_bytes >= before.fallback_free_bytes + reclaimed);
+}
+
+#[test_case]
+fn growth_fails_while_mapper_is_locked() {
+    let size = os::memory::HEAP_MAX_SIZE / 2;
+    let mut buffer: Vec<u8> = Vec::new();
+    {
+        // Growing the heap needs the mapper, so this must fail instead of deadlocking
+        let _mapper = os::memory::mapper().lock();
+        assert!(buffer.try_reserve_exact(size).is_err());
+    }
+    assert!(buffer.try_reserve_exact(size).is_ok());
+}
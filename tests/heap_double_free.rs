@@ -0,0 +1,35 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use os::{qemu, serial_print, serial_println};
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    qemu::exit(qemu::ExitCode::Success);
+    os::halt();
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("heap_double_free::double_free_is_detected... ");
+    os::memory::initialize_heap_allocator(boot_info);
+
+    let layout = Layout::new::<u64>();
+    unsafe {
+        let ptr = alloc(layout);
+        dealloc(ptr, layout);
+        dealloc(ptr, layout);
+    }
+
+    serial_println!("[double free was not detected]");
+    qemu::exit(qemu::ExitCode::Failed);
+    os::halt();
+}
+
+entry_point!(main);
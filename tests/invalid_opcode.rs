@@ -0,0 +1,54 @@
+#![no_std]
+#![no_main]
+
+use core::{arch::asm, fmt::Write, panic::PanicInfo};
+
+use os::{qemu, serial_print, serial_println};
+
+/// Collects the start of the panic message, which is enough to hold the exception name.
+struct MessageBuffer {
+    bytes: [u8; 256],
+    len: usize,
+}
+
+impl Write for MessageBuffer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let count = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+// The kernel's invalid opcode handler panics with a report of the exception, which should name
+// it instead of escalating to a double fault.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = MessageBuffer {
+        bytes: [0; 256],
+        len: 0,
+    };
+    let _ = write!(message, "{}", info);
+    let message = core::str::from_utf8(&message.bytes[..message.len]).unwrap_or("");
+    if message.contains("EXCEPTION: invalid opcode (#UD)") {
+        serial_println!("[ok]");
+        qemu::exit(qemu::ExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("Unexpected panic: {}", info);
+        qemu::exit(qemu::ExitCode::Failed);
+    }
+    os::halt();
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("invalid_opcode::invalid_opcode... ");
+
+    os::interrupt::initialize_interrupt_descriptor_table();
+    unsafe { asm!("ud2") };
+
+    serial_println!("[test did not panic]");
+    qemu::exit(qemu::ExitCode::Failed);
+    os::halt();
+}
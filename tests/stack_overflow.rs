@@ -37,10 +37,13 @@ extern "x86-interrupt" fn test_double_fault_handler(
     os::halt();
 }
 
-fn main(_boot_info: &'static BootInfo) -> ! {
+fn main(boot_info: &'static BootInfo) -> ! {
     serial_print!("stack_overflow::stack_overflow... ");
 
+    os::gdt::initialize_bootstrap_global_descriptor_table();
     TEST_IDT.load();
+    // The guarded double fault stack is allocated in kernel virtual memory
+    os::memory::initialize_heap_allocator(boot_info);
     os::gdt::initialize_global_descriptor_table();
 
     #[allow(unconditional_recursion)]
@@ -0,0 +1,56 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use conquer_once::spin::Lazy;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+use os::{qemu, serial_print, serial_println};
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::testing::test_panic_handler(info)
+}
+
+// Set up a custom interrupt descriptor table with a page fault handler that checks that the
+// fault was caused by writing to a read-only page, then exits qemu.
+static TEST_IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
+    let mut table = InterruptDescriptorTable::new();
+    table.page_fault.set_handler_fn(test_page_fault_handler);
+    table
+});
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    if error_code
+        .contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+    {
+        serial_println!("[ok]");
+        qemu::exit(qemu::ExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("Unexpected page fault: {:?}", error_code);
+        qemu::exit(qemu::ExitCode::Failed);
+    }
+    os::halt();
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("write_to_code::write_to_code... ");
+
+    os::memory::initialize_heap_allocator(boot_info);
+    os::memory::enforce_write_xor_execute();
+    TEST_IDT.load();
+
+    let code = main as fn(&'static BootInfo) -> ! as *mut u8;
+    unsafe { code.write_volatile(0xc3) };
+
+    panic!("Continued running after writing to code!");
+}
+
+entry_point!(main);